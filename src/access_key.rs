@@ -0,0 +1,77 @@
+use crate::prometheus::ACCESS_KEY_FAILURES;
+use crate::structs::FirehoseError;
+use axum::http::HeaderMap;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+pub const ACCESS_KEY_HEADER: &str = "x-amz-firehose-access-key";
+
+/// Collects the access keys firehose is allowed to present: the configured list (or the
+/// comma-separated `FIREHOSE_ACCESS_KEYS`), merged with a file (e.g. a mounted k8s secret) with
+/// one key per line.
+pub fn load_access_keys(list: &[String], file: Option<&Path>) -> anyhow::Result<Vec<String>> {
+    let mut keys: Vec<String> = list.iter().map(|s| s.trim().to_string()).collect();
+    if let Some(path) = file {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Couldn't read access keys from {path:?}: {e}"))?;
+        keys.extend(contents.lines().map(|s| s.trim().to_string()));
+    }
+    keys.retain(|k| !k.is_empty());
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+/// Checks the access key header against the configured keys.  With no keys configured, every
+/// request is let through so that existing deployments keep working.
+pub fn validate_access_key(headers: &HeaderMap, keys: &[String]) -> Result<(), FirehoseError> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let presented = match headers.get(ACCESS_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(k) => k,
+        None => {
+            ACCESS_KEY_FAILURES.with_label_values(&["missing"]).inc();
+            return Err(FirehoseError::MissingAccessKey);
+        }
+    };
+    // check every key so that response timing doesn't leak which one came close
+    let mut matched = false;
+    for key in keys.iter() {
+        matched |= constant_time_eq(presented.as_bytes(), key.as_bytes());
+    }
+    if !matched {
+        ACCESS_KEY_FAILURES.with_label_values(&["invalid"]).inc();
+        return Err(FirehoseError::InvalidAccessKey);
+    }
+    Ok(())
+}
+
+/// Compares digests of the two, which are always the same length, so that a key's length doesn't
+/// show in the timing either.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn test_validate_access_key() {
+    let keys = vec!["secret-one".to_string(), "secret-two".to_string()];
+    let mut headers = HeaderMap::new();
+    assert!(matches!(
+        validate_access_key(&headers, &keys),
+        Err(FirehoseError::MissingAccessKey)
+    ));
+    headers.insert(ACCESS_KEY_HEADER, "secret-three".parse().unwrap());
+    assert!(matches!(
+        validate_access_key(&headers, &keys),
+        Err(FirehoseError::InvalidAccessKey)
+    ));
+    // a prefix of a key is still the wrong key
+    headers.insert(ACCESS_KEY_HEADER, "secret".parse().unwrap());
+    assert!(validate_access_key(&headers, &keys).is_err());
+    headers.insert(ACCESS_KEY_HEADER, "secret-two".parse().unwrap());
+    assert!(validate_access_key(&headers, &keys).is_ok());
+    assert!(validate_access_key(&HeaderMap::new(), &[]).is_ok());
+}
@@ -0,0 +1,154 @@
+use crate::config::DestinationConfig;
+use axum::http::{HeaderName, HeaderValue};
+use reqwest::RequestBuilder;
+use std::fs;
+use std::path::PathBuf;
+
+/// A credential given either inline or as a file.  Files are read for every request, so a
+/// rotated secret is used as soon as it lands on disk.
+#[derive(Debug, Clone)]
+pub enum Secret {
+    Inline(String),
+    File(PathBuf),
+}
+
+impl Secret {
+    fn from_config(inline: Option<&String>, file: Option<&PathBuf>) -> Option<Self> {
+        match (inline, file) {
+            (Some(value), _) => Some(Secret::Inline(value.clone())),
+            (None, Some(path)) => Some(Secret::File(path.clone())),
+            (None, None) => None,
+        }
+    }
+
+    fn read(&self) -> anyhow::Result<String> {
+        match self {
+            Secret::Inline(value) => Ok(value.clone()),
+            Secret::File(path) => Ok(fs::read_to_string(path)
+                .map_err(|e| anyhow!("Couldn't read {path:?}: {e}"))?
+                .trim_end()
+                .to_string()),
+        }
+    }
+}
+
+/// What gets added to every write request to one destination.
+#[derive(Debug, Clone, Default)]
+pub struct AuthSettings {
+    basic: Option<(String, Option<Secret>)>,
+    bearer: Option<Secret>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl AuthSettings {
+    /// Headers that don't parse are left out here; `Config::validate` has already refused them.
+    pub fn from_config(config: &DestinationConfig) -> Self {
+        AuthSettings {
+            basic: config.basic_auth.as_ref().map(|basic| {
+                (
+                    basic.username.clone(),
+                    Secret::from_config(basic.password.as_ref(), basic.password_file.as_ref()),
+                )
+            }),
+            bearer: Secret::from_config(config.bearer_token.as_ref(), config.bearer_token_file.as_ref()),
+            headers: config
+                .headers
+                .iter()
+                .filter_map(|(name, value)| Some((name.parse().ok()?, value.parse().ok()?)))
+                .collect(),
+        }
+    }
+
+    pub fn apply(&self, mut request: RequestBuilder) -> anyhow::Result<RequestBuilder> {
+        for (name, value) in self.headers.iter() {
+            request = request.header(name, value);
+        }
+        if let Some((username, password)) = self.basic.as_ref() {
+            let password = password.as_ref().map(Secret::read).transpose()?;
+            request = request.basic_auth(username, password);
+        }
+        if let Some(token) = self.bearer.as_ref() {
+            request = request.bearer_auth(token.read()?);
+        }
+        Ok(request)
+    }
+}
+
+#[test]
+fn test_secret_file_is_reread() {
+    let dir = crate::testing::TempDir::new("token");
+    let path = dir.join("token");
+    fs::write(&path, "first\n").unwrap();
+    let secret = Secret::File(path.clone());
+    assert_eq!(secret.read().unwrap(), "first");
+    fs::write(&path, "second\n").unwrap();
+    assert_eq!(secret.read().unwrap(), "second");
+    fs::remove_file(&path).unwrap();
+    assert!(secret.read().is_err());
+}
+
+#[tokio::test]
+async fn test_env_credentials_replace_the_files() {
+    use crate::config::Config;
+    use crate::flusher::FlusherSettings;
+    use crate::prometheus::send_write_request;
+    use crate::remote_write::RemoteWriteProtocol;
+    use crate::testing::{CaptureServer, TempDir};
+    use axum::body::Bytes;
+
+    let server = CaptureServer::start().await;
+    let addr = server.addr;
+    let dir = TempDir::new("auth");
+    let path = dir.join("config.yaml");
+    fs::write(
+        &path,
+        format!(
+            r#"
+destinations:
+  - name: basic
+    addr: http://{addr}/basic
+    bearer_token_file: /nonexistent/token
+    headers:
+      X-From-File: file
+  - name: bearer
+    addr: http://{addr}/bearer
+    basic_auth:
+      username: bob
+      password_file: /nonexistent/password
+"#
+        ),
+    )
+    .unwrap();
+    let lookup = |name: &str| {
+        let value = match name {
+            "BASIC_PROM_USERNAME" => "alice",
+            "BASIC_PROM_PASSWORD" => "s3cret",
+            "BASIC_REMOTE_WRITE_HEADERS" => "X-From-Env=env",
+            "BEARER_PROM_BEARER_TOKEN" => "t0ken",
+            _ => return None,
+        };
+        Some(value.to_string())
+    };
+    let config = Config::load_with(Some(&path), &lookup).unwrap();
+    config.validate().unwrap();
+
+    for destination in config.destinations.iter() {
+        let settings = FlusherSettings::from_config(destination).unwrap();
+        send_write_request(&settings, Bytes::from_static(b"payload"), RemoteWriteProtocol::V1, None)
+            .await
+            .unwrap();
+    }
+    let requests = server.requests();
+    let headers = |prefix: &str| {
+        &requests
+            .iter()
+            .find(|r| r.uri.path().starts_with(prefix))
+            .unwrap()
+            .headers
+    };
+    let basic = headers("/basic");
+    assert_eq!(basic["authorization"], "Basic YWxpY2U6czNjcmV0");
+    assert_eq!(basic["x-from-file"], "file");
+    assert_eq!(basic["x-from-env"], "env");
+    assert_eq!(headers("/bearer")["authorization"], "Bearer t0ken");
+}
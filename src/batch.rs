@@ -0,0 +1,237 @@
+use crate::config::FilterConfig;
+use crate::naming::MetricNamer;
+use crate::prometheus::to_timeseries;
+use crate::relabel::Relabeler;
+use crate::remote_write::METRIC_NAME_LABEL;
+use crate::structs::{CloudWatchMetric, MetricUnit};
+use prometheus_remote_write::TimeSeries;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+type SeriesKey = Vec<(String, String)>;
+
+/// Where a series came from, which is what decides the tenant it's sent to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Source {
+    pub account_id: String,
+    pub region: String,
+    pub metric_stream_name: String,
+}
+
+impl Source {
+    fn of(metric: &CloudWatchMetric) -> Self {
+        Source {
+            account_id: metric.account_id.clone(),
+            region: metric.region.clone(),
+            metric_stream_name: metric.metric_stream_name.clone(),
+        }
+    }
+}
+
+/// The series converted from firehose deliveries, one batch per delivery until the flusher merges
+/// them.  Series are kept apart by source until split by tenant, since relabeling may hide it.
+#[derive(Debug, Default, Clone)]
+pub struct MetricBatch {
+    series: HashMap<(Source, SeriesKey), TimeSeries>,
+    units: HashMap<String, MetricUnit>,
+    samples: usize,
+}
+
+impl MetricBatch {
+    /// Converts everything the filters let through.
+    pub fn from_metrics(
+        metrics: &[CloudWatchMetric],
+        naming: &MetricNamer,
+        filters: &FilterConfig,
+        relabel: &Relabeler,
+    ) -> Self {
+        let mut batch = MetricBatch::default();
+        for metric in metrics.iter().filter(|m| filters.allows(m)) {
+            batch.add(metric, naming, relabel);
+        }
+        batch
+    }
+
+    /// Converts one metric and relabels the series it makes, leaving out any that get dropped.
+    pub fn add(&mut self, metric: &CloudWatchMetric, naming: &MetricNamer, relabel: &Relabeler) {
+        let source = Source::of(metric);
+        for mut series in to_timeseries(metric, naming) {
+            if !relabel.is_empty() {
+                match relabel.apply(std::mem::take(&mut series.labels)) {
+                    Some(labels) => series.labels = labels,
+                    None => continue,
+                }
+            }
+            if let Some(name) = metric_name(&series) {
+                self.units.insert(name.to_string(), metric.unit.clone());
+            }
+            self.push(source.clone(), series);
+        }
+    }
+
+    fn push(&mut self, source: Source, series: TimeSeries) {
+        self.samples += series.samples.len();
+        let key: SeriesKey = series
+            .labels
+            .iter()
+            .map(|l| (l.name.clone(), l.value.clone()))
+            .collect();
+        match self.series.entry((source, key)) {
+            Entry::Occupied(mut existing) => existing.get_mut().samples.extend(series.samples),
+            Entry::Vacant(entry) => {
+                entry.insert(series);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: MetricBatch) {
+        self.units.extend(other.units);
+        for ((source, _), series) in other.series {
+            self.push(source, series);
+        }
+    }
+
+    /// Splits the batch up by whatever `key` makes of each series' source.
+    pub fn split_by<K: Hash + Eq>(self, key: impl Fn(&Source) -> K) -> HashMap<K, MetricBatch> {
+        let mut batches: HashMap<K, MetricBatch> = HashMap::new();
+        for ((source, _), series) in self.series {
+            let batch = batches.entry(key(&source)).or_insert_with(|| MetricBatch {
+                units: self.units.clone(),
+                ..Default::default()
+            });
+            batch.push(source, series);
+        }
+        batches
+    }
+
+    /// Relabels every series again, as a destination's own `relabel_configs` do.  Series that
+    /// end up with the same labels are folded together, and units follow renamed metrics.
+    pub fn relabel(self, relabel: &Relabeler) -> MetricBatch {
+        if relabel.is_empty() {
+            return self;
+        }
+        let mut batch = MetricBatch::default();
+        for ((source, _), mut series) in self.series {
+            let unit = metric_name(&series).and_then(|name| self.units.get(name));
+            let Some(labels) = relabel.apply(std::mem::take(&mut series.labels)) else {
+                continue;
+            };
+            series.labels = labels;
+            if let (Some(name), Some(unit)) = (metric_name(&series), unit) {
+                batch.units.insert(name.to_string(), unit.clone());
+            }
+            batch.push(source, series);
+        }
+        batch
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    /// The number of samples added, before duplicates are dropped.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// The cloudwatch unit behind each metric name in the batch.
+    pub fn units(&self) -> &HashMap<String, MetricUnit> {
+        &self.units
+    }
+
+    /// The merged series, with samples in timestamp order, for sending in one request.  Series
+    /// with the same labels are folded together whatever their source, and if cloudwatch sent
+    /// the same point twice only the last one is kept, since receivers reject duplicates.
+    pub fn into_series(self) -> Vec<TimeSeries> {
+        let mut merged: HashMap<SeriesKey, TimeSeries> = HashMap::new();
+        for ((_, key), series) in self.series {
+            match merged.get_mut(&key) {
+                Some(existing) => existing.samples.extend(series.samples),
+                None => {
+                    merged.insert(key, series);
+                }
+            }
+        }
+        merged
+            .into_values()
+            .map(|mut s| {
+                s.samples.reverse();
+                s.samples.sort_by_key(|sample| sample.timestamp);
+                s.samples.dedup_by_key(|sample| sample.timestamp);
+                s
+            })
+            .collect()
+    }
+}
+
+pub fn metric_name(series: &TimeSeries) -> Option<&str> {
+    series
+        .labels
+        .iter()
+        .find(|l| l.name == METRIC_NAME_LABEL)
+        .map(|l| l.value.as_str())
+}
+
+#[test]
+fn test_batch_merges_series() {
+    use crate::structs::DimensionMap;
+    use crate::testing::cpu_metric;
+    let metric = |timestamp: i64, max: f64| CloudWatchMetric {
+        dimensions: DimensionMap([("InstanceId".to_string(), "i-0123".to_string())].into()),
+        timestamp,
+        ..cpu_metric(max)
+    };
+    let naming = MetricNamer::default();
+    let relabel = Relabeler::default();
+    let mut batch = MetricBatch::default();
+    batch.add(&metric(1_000, 1.0), &naming, &relabel);
+    let mut other = MetricBatch::default();
+    other.add(&metric(2_000, 2.0), &naming, &relabel);
+    other.add(&metric(1_000, 3.0), &naming, &relabel);
+    batch.merge(other);
+    assert_eq!(batch.samples(), 3);
+    assert!(matches!(
+        batch.units().get("firehose_ec2_cpuutilization_percent_max"),
+        Some(MetricUnit::Percent)
+    ));
+
+    let series = batch.into_series();
+    assert_eq!(series.len(), 1);
+    let points: Vec<(i64, f64)> = series[0].samples.iter().map(|s| (s.timestamp, s.value)).collect();
+    assert_eq!(points, vec![(1_000, 3.0), (2_000, 2.0)]);
+}
+
+#[test]
+fn test_batch_keeps_sources_apart_after_relabeling() {
+    use crate::relabel::RelabelConfig;
+    use crate::testing::cpu_metric;
+    let metric = |account_id: &str, max: f64| CloudWatchMetric {
+        account_id: account_id.to_string(),
+        metric_stream_name: format!("stream-{account_id}"),
+        timestamp: 1_000,
+        ..cpu_metric(max)
+    };
+    let relabel = Relabeler::new(&[RelabelConfig {
+        regex: "account_id|metric_stream_name".to_string(),
+        action: crate::relabel::RelabelAction::LabelDrop,
+        ..Default::default()
+    }])
+    .unwrap();
+    let naming = MetricNamer::default();
+    let mut batch = MetricBatch::default();
+    batch.add(&metric("111111111111", 1.0), &naming, &relabel);
+    batch.add(&metric("222222222222", 2.0), &naming, &relabel);
+
+    let by_account = batch.clone().split_by(|source| source.account_id.clone());
+    for (account_id, max) in [("111111111111", 1.0), ("222222222222", 2.0)] {
+        let series = by_account[account_id].clone().into_series();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].samples.len(), 1);
+        assert_eq!(series[0].samples[0].value, max);
+    }
+    // sent together, they're the same series
+    let series = batch.into_series();
+    assert_eq!(series.len(), 1);
+    assert_eq!(series[0].samples.len(), 1);
+}
@@ -0,0 +1,240 @@
+use crate::batch::MetricBatch;
+use crate::config::Config;
+use crate::flusher::FlusherSettings;
+use crate::naming::MetricNamer;
+use crate::prometheus::series_to_text;
+use crate::relabel::Relabeler;
+use crate::retry::{send_with_retry, SendOutcome};
+use crate::structs::{Firehose, MetricStreamFormat};
+use crate::decode_delivery;
+use axum::body::Bytes;
+use clap::{Parser, Subcommand, ValueEnum};
+use flate2::read::GzDecoder;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Receives CloudWatch metric streams from Kinesis Data Firehose and forwards them to
+/// Prometheus remote-write receivers.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the yaml config file.
+    #[arg(long, short, env = "CONFIG_FILE", global = true)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, overrides `listen` from the config file and LISTEN_ADDR.
+    #[arg(long, global = true)]
+    pub listen: Option<String>,
+    /// Plain text logs, or one json object per line.
+    #[arg(long, value_enum, env = "LOG_FORMAT", default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Accept firehose deliveries and forward them, the default.
+    Serve,
+    /// Print the metrics in a captured firehose delivery in the prometheus text format.
+    Convert {
+        /// A firehose request body, gzipped or not.
+        file: PathBuf,
+        /// How the records are encoded, overriding `metric_stream_format` from the config.
+        #[arg(long)]
+        format: Option<MetricStreamFormat>,
+    },
+    /// Send captured firehose deliveries to the configured destinations.
+    Replay {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Only send to the destination with this name.
+        #[arg(long)]
+        destination: Option<String>,
+    },
+    /// Load and validate the configuration, then exit.
+    CheckConfig,
+}
+
+impl Cli {
+    /// The configuration from the file and environment, with the command line applied on top.
+    pub fn load_config(&self) -> anyhow::Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(listen) = self.listen.as_ref() {
+            config.listen = listen.clone();
+        }
+        Ok(config)
+    }
+}
+
+/// Reads a captured delivery the same way the listener would parse one.
+fn read_delivery(path: &Path) -> anyhow::Result<Firehose> {
+    let raw = fs::read(path).map_err(|e| anyhow!("Couldn't read {path:?}: {e}"))?;
+    let body = match raw.starts_with(&[0x1f, 0x8b]) {
+        true => {
+            let mut body = vec![];
+            GzDecoder::new(raw.as_slice())
+                .read_to_end(&mut body)
+                .map_err(|e| anyhow!("Couldn't gunzip {path:?}: {e}"))?;
+            body
+        }
+        false => raw,
+    };
+    serde_json::from_slice(&body).map_err(|e| anyhow!("{path:?} isn't a firehose delivery: {e}"))
+}
+
+async fn convert_delivery(config: &Config, path: &Path, format: MetricStreamFormat) -> anyhow::Result<MetricBatch> {
+    let payload = read_delivery(path)?;
+    let request_id = payload.request_id.clone().unwrap_or_default();
+    let (metrics, failed, total) = decode_delivery(payload, format, &request_id).await;
+    if failed > 0 {
+        warn!("{failed} of {total} records in {path:?} couldn't be decoded.");
+    }
+    let naming = MetricNamer::new(&config.naming)?;
+    let relabel = Relabeler::new(&config.relabel_configs)?;
+    Ok(MetricBatch::from_metrics(&metrics, &naming, &config.filters, &relabel))
+}
+
+pub async fn convert(cli: &Cli, file: &Path, format: Option<MetricStreamFormat>) -> anyhow::Result<()> {
+    // only naming and filters matter here, so a config without destinations is fine
+    let config = cli.load_config()?;
+    let format = format.unwrap_or(config.metric_stream_format);
+    let batch = convert_delivery(&config, file, format).await?;
+    print!("{}", series_to_text(&batch.into_series()));
+    Ok(())
+}
+
+/// Sends each file as its own write request, with the destination's retry settings but without
+/// its queue or wal.  Fails if any file couldn't be delivered to any destination.
+pub async fn replay(cli: &Cli, files: &[PathBuf], destination: Option<&str>) -> anyhow::Result<()> {
+    let config = cli.load_config()?;
+    config.validate()?;
+    let destinations: Vec<FlusherSettings> = config
+        .destinations
+        .iter()
+        .filter(|d| destination.is_none_or(|name| d.name == name))
+        .map(FlusherSettings::from_config)
+        .collect::<anyhow::Result<_>>()?;
+    if destinations.is_empty() {
+        bail!("No destination named {:?} in the config.", destination.unwrap_or_default());
+    }
+
+    let mut failures: usize = 0;
+    for file in files {
+        let batch = convert_delivery(&config, file, config.metric_stream_format).await?;
+        if batch.is_empty() {
+            info!("Nothing to send from {file:?}.");
+            continue;
+        }
+        for settings in destinations.iter() {
+            let mut delivered = true;
+            for (tenant, batch) in settings.split(batch.clone()) {
+                let samples = batch.samples();
+                let body = Bytes::from(settings.protocol.encode(batch)?);
+                match send_with_retry(settings, body, settings.protocol, tenant.as_ref()).await {
+                    SendOutcome::Delivered => info!("Sent {samples} samples from {file:?} to {}.", settings.name),
+                    outcome => {
+                        error!("Couldn't send {file:?} to {}: {outcome:?}", settings.name);
+                        delivered = false;
+                    }
+                }
+            }
+            if !delivered {
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("{failures} of {} replays failed.", files.len() * destinations.len());
+    }
+    Ok(())
+}
+
+pub fn check_config(cli: &Cli) -> anyhow::Result<()> {
+    let config = cli.load_config()?;
+    config.validate()?;
+    println!("Configuration is valid.");
+    println!("listen: {}", config.listen);
+    if let Some(tls) = config.tls.as_ref() {
+        println!("tls listen: {} ({:?})", tls.listen, tls.cert_file);
+    }
+    for destination in config.destinations.iter() {
+        println!(
+            "destination {}: {} (remote write {})",
+            destination.name, destination.write_url(), destination.protocol
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_convert_delivery() {
+    let path = Path::new("testdata/just-post-payload.json");
+    let batch = convert_delivery(&Config::default(), path, MetricStreamFormat::Json)
+        .await
+        .unwrap();
+    let text = series_to_text(&batch.into_series());
+    // two records, three metrics, four statistics each
+    assert_eq!(text.lines().filter(|l| !l.starts_with('#')).count(), 12);
+    assert!(text.contains("# TYPE firehose_ec2_cpuutilization_percent_max gauge\n"));
+    assert!(text.contains(
+        "firehose_ec2_cpuutilization_percent_max{account_id=\"111111111111\",instance_id=\"i-0123456789abcdef0\",\
+         metric_stream_name=\"all-metrics\",region=\"us-east-1\"} 42.5 1700000000000\n"
+    ));
+    assert!(text.contains(
+        "firehose_applicationelb_requestcount_count_sum{account_id=\"111111111111\",availability_zone=\"us-east-1a\",\
+         load_balancer=\"app/web/0123456789abcdef\",metric_stream_name=\"all-metrics\",region=\"us-east-1\"} 30 \
+         1700000060000\n"
+    ));
+}
+
+#[tokio::test]
+async fn test_replay_sends_to_each_destination() {
+    use crate::testing::{CaptureServer, TempDir};
+
+    let server = CaptureServer::start().await;
+    let addr = server.addr;
+    let dir = TempDir::new("replay");
+    let path = dir.join("config.yaml");
+    fs::write(
+        &path,
+        format!(
+            r#"
+destinations:
+  - name: mimir
+    addr: http://{addr}
+    path: /api/v1/push
+  - name: victoria
+    addr: http://{addr}
+"#
+        ),
+    )
+    .unwrap();
+    let cli = Cli {
+        config: Some(path.clone()),
+        listen: None,
+        log_format: LogFormat::Text,
+        command: None,
+    };
+    let files = [PathBuf::from("testdata/just-post-payload.json")];
+    replay(&cli, &files, Some("mimir")).await.unwrap();
+    replay(&cli, &files, None).await.unwrap();
+    assert!(replay(&cli, &files, Some("staging")).await.is_err());
+
+    let requests = server.requests();
+    let paths: Vec<&str> = requests.iter().map(|r| r.uri.path()).collect();
+    assert_eq!(paths, vec!["/api/v1/push", "/api/v1/push", "/api/v1/write"]);
+    for r in requests.iter() {
+        assert_eq!(r.headers["content-encoding"], "snappy");
+        let request = snap::raw::Decoder::new().decompress_vec(&r.body).unwrap();
+        let contains = |needle: &str| request.windows(needle.len()).any(|w| w == needle.as_bytes());
+        assert!(contains("firehose_ec2_cpuutilization_percent_max"));
+        assert!(contains("i-0123456789abcdef0"));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::{ClientTlsConfig, DestinationConfig};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Client, Proxy};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use url::{Host, Url};
+
+/// Builds the http client for one destination.  A flusher keeps its client for as long as it
+/// runs, so connections to the receiver are pooled and reused from one push to the next.
+pub fn build_client(config: &DestinationConfig) -> anyhow::Result<Client> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .timeout(Duration::from_secs(config.timeout_secs));
+    if let Some(proxy) = config.proxy_url.as_ref() {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    if let Some(tls) = config.tls.as_ref() {
+        builder = builder.use_preconfigured_tls(tls_config(tls)?);
+        if let Some(server_name) = tls.server_name.as_ref() {
+            builder = builder.dns_resolver(Arc::new(PinnedResolver {
+                server_name: server_name.clone(),
+                host: addr_host(&config.addr)?,
+            }));
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Where writes are posted.  A `tls.server_name` replaces the host, so it's what SNI and the
+/// certificate check use; `build_client` still connects to the host in `addr`.
+pub fn request_url(config: &DestinationConfig) -> anyhow::Result<String> {
+    let url = config.write_url();
+    let Some(server_name) = config.tls.as_ref().and_then(|tls| tls.server_name.as_ref()) else {
+        return Ok(url);
+    };
+    let mut url = Url::parse(&url)?;
+    url.set_host(Some(server_name))?;
+    Ok(url.to_string())
+}
+
+fn addr_host(addr: &str) -> anyhow::Result<String> {
+    Ok(match Url::parse(addr)?.host() {
+        Some(Host::Domain(domain)) => domain.to_string(),
+        Some(Host::Ipv4(ip)) => ip.to_string(),
+        Some(Host::Ipv6(ip)) => ip.to_string(),
+        None => bail!("{addr:?} has no host"),
+    })
+}
+
+/// Looks up the host in `addr` whenever `server_name` is asked for, and anything else, such as
+/// a proxy, as usual.  Ports come from the url, so the ones here don't matter.
+#[derive(Debug)]
+struct PinnedResolver {
+    server_name: String,
+    host: String,
+}
+
+impl Resolve for PinnedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = match name.as_str().eq_ignore_ascii_case(&self.server_name) {
+            true => self.host.clone(),
+            false => name.as_str().to_string(),
+        };
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Couldn't read certificates from {path:?}: {e}"))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {path:?}");
+    }
+    Ok(certs)
+}
+
+fn tls_config(config: &ClientTlsConfig) -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match config.ca_file.as_ref() {
+        Some(path) => {
+            for cert in read_certs(path)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    Ok(match (config.cert_file.as_ref(), config.key_file.as_ref()) {
+        (Some(cert_file), Some(key_file)) => {
+            let key = PrivateKeyDer::from_pem_file(key_file)
+                .map_err(|e| anyhow!("Couldn't read private key from {key_file:?}: {e}"))?;
+            builder.with_client_auth_cert(read_certs(cert_file)?, key)?
+        }
+        _ => builder.with_no_client_auth(),
+    })
+}
+
+#[tokio::test]
+async fn test_mutual_tls_with_server_name_override() {
+    use axum_server::tls_rustls::RustlsConfig;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+    use rustls::sign::CertifiedKey;
+    use rustls::ServerConfig;
+    use std::fs;
+    use std::sync::Mutex;
+
+    /// Serves one certificate, and remembers the name the client asked for.
+    #[derive(Debug)]
+    struct RecordServerName {
+        key: Arc<CertifiedKey>,
+        asked_for: Mutex<Option<String>>,
+    }
+    impl ResolvesServerCert for RecordServerName {
+        fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            *self.asked_for.lock().unwrap() = client_hello.server_name().map(String::from);
+            Some(Arc::clone(&self.key))
+        }
+    }
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let issue = |name: &str| {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        (cert, key)
+    };
+    let (server_cert, server_key) = issue("thanos-receive.internal");
+    let (client_cert, client_key) = issue("firehose");
+
+    // a receiver that only talks to clients with a certificate from the same ca
+    let provider = Arc::new(ring::default_provider());
+    let mut client_roots = RootCertStore::empty();
+    client_roots.add(ca.der().clone()).unwrap();
+    let client_verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(client_roots), provider.clone())
+        .build()
+        .unwrap();
+    let key = provider
+        .key_provider
+        .load_private_key(PrivatePkcs8KeyDer::from(server_key.serialize_der()).into())
+        .unwrap();
+    let resolver = Arc::new(RecordServerName {
+        key: Arc::new(CertifiedKey::new(vec![server_cert.der().clone()], key)),
+        asked_for: Mutex::new(None),
+    });
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_client_cert_verifier(client_verifier)
+        .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+    let app = axum::Router::new().route("/api/v1/write", axum::routing::post(|| async { "" }));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum_server::from_tcp_rustls(listener, RustlsConfig::from_config(Arc::new(server_config)));
+    tokio::spawn(server.serve(app.into_make_service()));
+
+    let dir = std::env::temp_dir().join(format!("firehose-test-mtls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    fs::write(dir.join("cert.pem"), client_cert.pem()).unwrap();
+    fs::write(dir.join("key.pem"), client_key.serialize_pem()).unwrap();
+    let post = |with_cert: bool, server_name: Option<&str>| {
+        let config = DestinationConfig {
+            addr: format!("https://{addr}"),
+            tls: Some(ClientTlsConfig {
+                ca_file: Some(dir.join("ca.pem")),
+                cert_file: with_cert.then(|| dir.join("cert.pem")),
+                key_file: with_cert.then(|| dir.join("key.pem")),
+                server_name: server_name.map(String::from),
+            }),
+            ..Default::default()
+        };
+        let client = build_client(&config).unwrap();
+        async move { client.post(request_url(&config).unwrap()).send().await }
+    };
+
+    let response = post(true, Some("thanos-receive.internal")).await.unwrap();
+    assert!(response.status().is_success());
+    // still connected to 127.0.0.1, but asked for the receiver by name
+    assert_eq!(resolver.asked_for.lock().unwrap().as_deref(), Some("thanos-receive.internal"));
+    // the receiver's certificate isn't for 127.0.0.1
+    assert!(post(true, None).await.is_err());
+    // and it wants to see ours
+    assert!(post(false, Some("thanos-receive.internal")).await.is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
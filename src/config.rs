@@ -0,0 +1,1028 @@
+use crate::consts::{
+    DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_DESTINATION, DEFAULT_FLUSH_INTERVAL_SECS, DEFAULT_FLUSH_MAX_SAMPLES,
+    DEFAULT_LISTEN_ADDR, DEFAULT_MAX_DECOMPRESSED_BYTES, DEFAULT_NAME_TEMPLATE, DEFAULT_QUEUE_CAPACITY,
+    DEFAULT_REMOTE_WRITE_TIMEOUT_SECS, DEFAULT_RETRY_MAX_AGE_SECS, DEFAULT_RETRY_MAX_ATTEMPTS,
+    DEFAULT_RETRY_MAX_BACKOFF_MS, DEFAULT_RETRY_MIN_BACKOFF_MS, DEFAULT_SIGV4_SERVICE, DEFAULT_SIGV4_WRITE_PATH,
+    DEFAULT_TENANT, DEFAULT_TENANT_HEADER, DEFAULT_TLS_LISTEN_ADDR, DEFAULT_WAL_MAX_AGE_SECS, DEFAULT_WAL_MAX_BYTES,
+    DEFAULT_WRITE_PATH, PROM_NAMESPACE,
+};
+use crate::naming::MetricNamer;
+use crate::relabel::{RelabelConfig, Relabeler};
+use crate::remote_write::{RemoteWriteProtocol, HEADER_VERSION};
+use axum::http::{HeaderName, HeaderValue};
+use crate::structs::{CloudWatchMetric, MetricStreamFormat};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Everything the service can be told, as read from the yaml config file.  Every field has a
+/// default, so an empty file (or no file at all) plus `PROM_WRITE_ADDR` is still a working setup.
+/// Precedence is defaults, then the file, then environment variables, then command line flags.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where to accept plain http, or empty to only accept https.
+    pub listen: String,
+    pub tls: Option<TlsConfig>,
+    pub access_keys: Vec<String>,
+    pub access_keys_file: Option<PathBuf>,
+    pub max_decompressed_bytes: usize,
+    #[serde(deserialize_with = "from_str")]
+    pub metric_stream_format: MetricStreamFormat,
+    pub naming: NamingConfig,
+    pub filters: FilterConfig,
+    /// Applied to every converted series, before it's queued for any destination.
+    pub relabel_configs: Vec<RelabelConfig>,
+    pub destinations: Vec<DestinationConfig>,
+}
+
+/// Serves https directly, for setups without an ingress in front to terminate tls.  The files are
+/// watched, so a renewed certificate is picked up without a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    #[serde(default = "default_tls_listen")]
+    pub listen: String,
+    /// PEM, with any intermediates after the certificate itself.
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+/// How forwarded metrics are named.  `template` takes `{prefix}`, `{namespace}`, `{metric}`,
+/// `{unit}` and `{stat}`, each with an optional case as in `{metric:snake}`; an empty one drops
+/// the separator after it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamingConfig {
+    /// Put in front of every forwarded metric name as written, `firehose` unless told otherwise.
+    pub prefix: String,
+    pub template: String,
+    /// For placeholders other than `{prefix}` that don't give their own.
+    pub case: NameCase,
+    /// Per cloudwatch namespace, e.g. `AWS/EC2` or `CWAgent`, replacing `template` or `case` for
+    /// its metrics, or what `{namespace}` stands for.
+    pub namespaces: BTreeMap<String, NamespaceNaming>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameCase {
+    /// `CPUUtilization` -> `cpuutilization`
+    #[default]
+    Lower,
+    /// `CPUUtilization` -> `CPUUTILIZATION`
+    Upper,
+    /// `CPUUtilization` -> `cpu_utilization`
+    Snake,
+    /// `CPUUtilization` -> `cpuUtilization`
+    Camel,
+    /// `CPUUtilization` -> `CpuUtilization`
+    Pascal,
+    /// Left as cloudwatch sent it.
+    Keep,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamespaceNaming {
+    /// Used for `{namespace}` as written, instead of what's made of the namespace itself.  Empty
+    /// leaves the namespace out of the name.
+    pub alias: Option<String>,
+    pub template: Option<String>,
+    pub case: Option<NameCase>,
+}
+
+/// Which cloudwatch namespaces get forwarded at all.  An empty include list lets everything
+/// through that isn't excluded.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub include_namespaces: Vec<String>,
+    pub exclude_namespaces: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DestinationConfig {
+    pub name: String,
+    /// The receiver's base address, `path` is appended.
+    pub addr: String,
+    /// Where on the receiver writes go, e.g. `/api/v1/push` for mimir or `/api/v1/receive` for
+    /// thanos.  Without it, `/api/v1/write`, or `/api/v1/remote_write` with sigv4.
+    pub path: Option<String>,
+    #[serde(deserialize_with = "from_str")]
+    pub protocol: RemoteWriteProtocol,
+    /// For the whole request, including sending the body and reading the response.
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    /// An http or https proxy to connect through.  Without one, the usual `HTTPS_PROXY` and
+    /// friends are respected.
+    pub proxy_url: Option<String>,
+    pub tls: Option<ClientTlsConfig>,
+    pub flush_interval_secs: u64,
+    pub flush_max_samples: usize,
+    pub queue_capacity: usize,
+    pub retry: RetryConfig,
+    pub wal: Option<WalConfig>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub bearer_token: Option<String>,
+    /// Re-read for every request, so a rotated token is picked up without a reload.
+    pub bearer_token_file: Option<PathBuf>,
+    /// Sent with every request, e.g. `X-Scope-OrgID` for a mimir or cortex tenant.
+    pub headers: BTreeMap<String, String>,
+    pub sigv4: Option<SigV4Config>,
+    pub tenants: Option<TenantConfig>,
+    /// Applied after the global `relabel_configs`, to what's sent to this destination only.
+    pub relabel_configs: Vec<RelabelConfig>,
+}
+
+/// How to talk tls to a receiver.  All files are PEM.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTlsConfig {
+    /// Trusted instead of the usual public roots.
+    pub ca_file: Option<PathBuf>,
+    /// A client certificate and key, for receivers that want mutual tls.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// Asked for in SNI and checked against the receiver's certificate instead of the host in
+    /// `addr`, which is still what's connected to.
+    pub server_name: Option<String>,
+}
+
+/// Sends each account's metrics to its own tenant, for receivers like mimir and cortex that
+/// take the tenant from a header.  Rules are tried in order; a source none of them match goes
+/// to `default`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    #[serde(default = "default_tenant_header")]
+    pub header: String,
+    #[serde(default = "default_tenant")]
+    pub default: String,
+    #[serde(default)]
+    pub rules: Vec<TenantRule>,
+}
+
+/// Matches an account, optionally narrowed down to a region or metric stream.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantRule {
+    pub account_id: String,
+    pub region: Option<String>,
+    pub metric_stream_name: Option<String>,
+    pub tenant: String,
+}
+
+/// Signs requests with AWS credentials, as Amazon Managed Service for Prometheus requires.
+/// Credentials come from the usual AWS chain: environment, profile, IRSA, instance profile.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigV4Config {
+    pub region: String,
+    #[serde(default = "default_sigv4_service")]
+    pub service: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub min_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WalConfig {
+    pub dir: PathBuf,
+    #[serde(default = "default_wal_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_wal_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: DEFAULT_LISTEN_ADDR.to_string(),
+            tls: None,
+            access_keys: vec![],
+            access_keys_file: None,
+            max_decompressed_bytes: DEFAULT_MAX_DECOMPRESSED_BYTES,
+            metric_stream_format: MetricStreamFormat::default(),
+            naming: NamingConfig::default(),
+            filters: FilterConfig::default(),
+            relabel_configs: vec![],
+            destinations: vec![],
+        }
+    }
+}
+
+impl Default for NamingConfig {
+    fn default() -> Self {
+        NamingConfig {
+            prefix: PROM_NAMESPACE.to_string(),
+            template: DEFAULT_NAME_TEMPLATE.to_string(),
+            case: NameCase::default(),
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for DestinationConfig {
+    fn default() -> Self {
+        DestinationConfig {
+            name: DEFAULT_DESTINATION.to_string(),
+            addr: String::new(),
+            path: None,
+            protocol: RemoteWriteProtocol::default(),
+            timeout_secs: DEFAULT_REMOTE_WRITE_TIMEOUT_SECS,
+            connect_timeout_secs: DEFAULT_CONNECT_TIMEOUT_SECS,
+            proxy_url: None,
+            tls: None,
+            flush_interval_secs: DEFAULT_FLUSH_INTERVAL_SECS,
+            flush_max_samples: DEFAULT_FLUSH_MAX_SAMPLES,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            retry: RetryConfig::default(),
+            wal: None,
+            basic_auth: None,
+            bearer_token: None,
+            bearer_token_file: None,
+            headers: BTreeMap::new(),
+            sigv4: None,
+            tenants: None,
+            relabel_configs: vec![],
+        }
+    }
+}
+
+impl Default for TenantConfig {
+    fn default() -> Self {
+        TenantConfig {
+            header: default_tenant_header(),
+            default: default_tenant(),
+            rules: vec![],
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: DEFAULT_RETRY_MAX_ATTEMPTS,
+            min_backoff_ms: DEFAULT_RETRY_MIN_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_RETRY_MAX_BACKOFF_MS,
+            max_age_secs: DEFAULT_RETRY_MAX_AGE_SECS,
+        }
+    }
+}
+
+fn default_tls_listen() -> String {
+    DEFAULT_TLS_LISTEN_ADDR.to_string()
+}
+
+fn default_tenant_header() -> String {
+    DEFAULT_TENANT_HEADER.to_string()
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+fn default_sigv4_service() -> String {
+    DEFAULT_SIGV4_SERVICE.to_string()
+}
+
+fn default_wal_max_bytes() -> u64 {
+    DEFAULT_WAL_MAX_BYTES
+}
+
+fn default_wal_max_age_secs() -> u64 {
+    DEFAULT_WAL_MAX_AGE_SECS
+}
+
+/// Set by the remote write protocol or the auth settings, so not up to `headers`.
+const RESERVED_HEADERS: [&str; 4] = ["Content-Type", "Content-Encoding", "Authorization", HEADER_VERSION];
+
+/// For the enums that already know how to parse themselves, like `MetricStreamFormat`.
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse()
+        .map_err(|_| serde::de::Error::custom(format!("unrecognized value {s:?}")))
+}
+
+impl Config {
+    /// Reads and validates the whole configuration.  Without a file, the defaults and the
+    /// environment are all there is.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        Config::load_with(path, &env_var)
+    }
+
+    /// `load`, with the environment read through `lookup`.
+    pub fn load_with(path: Option<&Path>, lookup: &dyn Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let raw = match path {
+            Some(path) => Some(read_file(path)?),
+            None => None,
+        };
+        Config::from_file(path.zip(raw.as_deref()), lookup)
+    }
+
+    /// What `load` makes of a config file's contents, for a caller that has already read them.
+    pub fn from_file(file: Option<(&Path, &str)>, lookup: &dyn Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let mut config = match file {
+            Some((path, raw)) => {
+                Config::parse_with(raw, lookup).map_err(|e| anyhow!("Couldn't load config file {path:?}: {e}"))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(lookup)?;
+        Ok(config)
+    }
+
+    /// Parses a config file's contents, replacing `${VAR}` and `${VAR:-default}` in its values
+    /// with what `lookup` has for them.
+    pub fn parse_with(raw: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let mut document: serde_yaml::Value = serde_yaml::from_str(raw)?;
+        if document.is_null() {
+            return Ok(Config::default());
+        }
+        if !interpolate_values(&mut document, &lookup)? {
+            return Ok(serde_yaml::from_str(raw)?);
+        }
+        // written out again rather than converted, so that a value from the environment is read
+        // as whatever its field needs, as it would be had it been written in the file
+        Ok(serde_yaml::from_str(&serde_yaml::to_string(&document)?)?)
+    }
+
+    /// Lets the environment variables the service has always read override the file.
+    fn apply_env(&mut self, lookup: &dyn Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        let env = EnvScope::new(lookup);
+        env.set("LISTEN_ADDR", &mut self.listen)?;
+        // like the wal, tls is switched on by pointing it at its files
+        if let (Some(cert_file), Some(key_file)) = (env.var("TLS_CERT_FILE"), env.var("TLS_KEY_FILE")) {
+            self.tls = Some(TlsConfig {
+                listen: default_tls_listen(),
+                cert_file: PathBuf::from(cert_file),
+                key_file: PathBuf::from(key_file),
+            });
+        }
+        if let Some(tls) = self.tls.as_mut() {
+            env.set("TLS_LISTEN_ADDR", &mut tls.listen)?;
+        }
+        if let Some(keys) = env.var("FIREHOSE_ACCESS_KEYS") {
+            self.access_keys = keys.split(',').map(|s| s.trim().to_string()).collect();
+        }
+        if let Some(path) = env.var("FIREHOSE_ACCESS_KEYS_FILE") {
+            self.access_keys_file = Some(PathBuf::from(path));
+        }
+        env.set("MAX_DECOMPRESSED_BYTES", &mut self.max_decompressed_bytes)?;
+        env.set("METRIC_STREAM_FORMAT", &mut self.metric_stream_format)?;
+        env.set("METRIC_PREFIX", &mut self.naming.prefix)?;
+        env.set("METRIC_NAME_TEMPLATE", &mut self.naming.template)?;
+
+        // PROM_WRITE_DESTINATIONS=mimir,staging names the destinations to set up from the
+        // environment, each read from MIMIR_PROM_WRITE_ADDR and so on.  Without it, and without
+        // any in the file, there is a single destination read from the plain variables.
+        let names: Vec<String> = match env.var("PROM_WRITE_DESTINATIONS") {
+            Some(names) => names
+                .split(',')
+                .map(|n| n.trim().to_string())
+                .filter(|n| !n.is_empty())
+                .collect(),
+            None if self.destinations.is_empty() => vec![DEFAULT_DESTINATION.to_string()],
+            None => vec![],
+        };
+        for name in names {
+            if self.destinations.iter().any(|d| d.name == name) {
+                continue;
+            }
+            self.destinations.push(DestinationConfig::from_env(&name, lookup)?);
+        }
+        // destinations from the file only take variables meant for them specifically
+        for destination in self.destinations.iter_mut() {
+            destination.apply_env(&env.for_destination(&destination.name, false))?;
+        }
+        Ok(())
+    }
+
+    /// Checks everything that can be checked before starting, and reports every problem at once.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems: Vec<String> = vec![];
+        if !self.listen.is_empty() && self.listen.parse::<SocketAddr>().is_err() {
+            problems.push(format!("listen: {:?} is not an address and port", self.listen));
+        }
+        match self.tls.as_ref() {
+            None if self.listen.is_empty() => {
+                problems.push("listen: can only be left empty when tls is set up".to_string());
+            }
+            None => {}
+            Some(tls) => {
+                if tls.listen.parse::<SocketAddr>().is_err() {
+                    problems.push(format!("tls.listen: {:?} is not an address and port", tls.listen));
+                } else if tls.listen == self.listen {
+                    problems.push("tls.listen: is the same address as listen".to_string());
+                }
+                if tls.cert_file.as_os_str().is_empty() {
+                    problems.push("tls.cert_file: can't be empty".to_string());
+                }
+                if tls.key_file.as_os_str().is_empty() {
+                    problems.push("tls.key_file: can't be empty".to_string());
+                }
+            }
+        }
+        if self.max_decompressed_bytes == 0 {
+            problems.push("max_decompressed_bytes: must be more than 0".to_string());
+        }
+        if !self.naming.prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            || self.naming.prefix.starts_with(|c: char| c.is_ascii_digit())
+        {
+            problems.push(format!(
+                "naming.prefix: {:?} is not a valid metric name prefix",
+                self.naming.prefix
+            ));
+        }
+        if let Err(e) = MetricNamer::new(&self.naming) {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = Relabeler::new(&self.relabel_configs) {
+            problems.push(e.to_string());
+        }
+        if self.destinations.is_empty() {
+            problems.push("destinations: at least one remote write destination is needed".to_string());
+        }
+        for (i, d) in self.destinations.iter().enumerate() {
+            let at = format!("destinations[{i}] ({})", d.name);
+            if d.name.is_empty() {
+                problems.push(format!("{at}: name can't be empty"));
+            }
+            if self.destinations.iter().filter(|o| o.name == d.name).count() > 1 {
+                problems.push(format!("{at}: name is used more than once"));
+            }
+            if d.addr.is_empty() {
+                problems.push(format!("{at}: addr is missing, set it in the file or PROM_WRITE_ADDR"));
+            } else if url::Url::parse(&d.addr).is_err() {
+                problems.push(format!("{at}: addr {:?} is not a url", d.addr));
+            }
+            if let Some(path) = d.path.as_ref() {
+                if !path.starts_with('/') || url::Url::parse(&d.write_url()).is_err() {
+                    problems.push(format!("{at}: path {path:?} is not a path starting with /"));
+                }
+            }
+            if d.timeout_secs == 0 {
+                problems.push(format!("{at}: timeout_secs must be more than 0"));
+            }
+            if d.connect_timeout_secs == 0 {
+                problems.push(format!("{at}: connect_timeout_secs must be more than 0"));
+            }
+            if let Some(proxy) = d.proxy_url.as_ref() {
+                if url::Url::parse(proxy).is_err() {
+                    problems.push(format!("{at}: proxy_url {proxy:?} is not a url"));
+                }
+            }
+            if let Some(tls) = d.tls.as_ref() {
+                if tls.cert_file.is_some() != tls.key_file.is_some() {
+                    problems.push(format!("{at}: tls.cert_file and tls.key_file go together"));
+                }
+                if let Some(name) = tls.server_name.as_ref() {
+                    if !d.addr.is_empty() && crate::client::request_url(d).is_err() {
+                        problems.push(format!("{at}: tls.server_name {name:?} is not a host name"));
+                    }
+                }
+            }
+            if d.flush_interval_secs == 0 {
+                problems.push(format!("{at}: flush_interval_secs must be more than 0"));
+            }
+            if d.queue_capacity == 0 {
+                problems.push(format!("{at}: queue_capacity must be more than 0"));
+            }
+            if d.retry.max_attempts == 0 {
+                problems.push(format!("{at}: retry.max_attempts must be at least 1"));
+            }
+            if d.retry.min_backoff_ms > d.retry.max_backoff_ms {
+                problems.push(format!("{at}: retry.min_backoff_ms is more than retry.max_backoff_ms"));
+            }
+            if let Some(basic) = d.basic_auth.as_ref() {
+                if basic.password.is_some() && basic.password_file.is_some() {
+                    problems.push(format!("{at}: basic_auth takes password or password_file, not both"));
+                }
+            }
+            if d.bearer_token.is_some() && d.bearer_token_file.is_some() {
+                problems.push(format!("{at}: bearer_token and bearer_token_file can't both be set"));
+            }
+            if d.basic_auth.is_some() && (d.bearer_token.is_some() || d.bearer_token_file.is_some()) {
+                problems.push(format!("{at}: basic_auth and a bearer token can't both be set"));
+            }
+            if let Some(sigv4) = d.sigv4.as_ref() {
+                if d.basic_auth.is_some() || d.bearer_token.is_some() || d.bearer_token_file.is_some() {
+                    problems.push(format!("{at}: sigv4 can't be combined with basic_auth or a bearer token"));
+                }
+                if sigv4.region.is_empty() {
+                    problems.push(format!("{at}: sigv4.region is missing"));
+                }
+                if sigv4.service.is_empty() {
+                    problems.push(format!("{at}: sigv4.service can't be empty"));
+                }
+            }
+            if let Some(tenants) = d.tenants.as_ref() {
+                match tenants.header.parse::<HeaderName>() {
+                    Ok(header) if RESERVED_HEADERS.iter().any(|r| r.eq_ignore_ascii_case(header.as_str())) => {
+                        problems.push(format!("{at}: tenants.header can't be {}", tenants.header));
+                    }
+                    Ok(_) => {}
+                    Err(_) => problems.push(format!("{at}: tenants.header {:?} is not a header name", tenants.header)),
+                }
+                if d.headers.keys().any(|name| name.eq_ignore_ascii_case(&tenants.header)) {
+                    problems.push(format!("{at}: headers can't set {} as well as tenants", tenants.header));
+                }
+                let tenant_ids = tenants.rules.iter().map(|r| &r.tenant).chain([&tenants.default]);
+                for tenant in tenant_ids {
+                    if tenant.is_empty() || tenant.parse::<HeaderValue>().is_err() {
+                        problems.push(format!("{at}: {tenant:?} is not a valid tenant"));
+                    }
+                }
+                for (j, rule) in tenants.rules.iter().enumerate() {
+                    if rule.account_id.is_empty() {
+                        problems.push(format!("{at}: tenants.rules[{j}].account_id is missing"));
+                    }
+                }
+            }
+            for (name, value) in d.headers.iter() {
+                match name.parse::<HeaderName>() {
+                    Ok(header) if RESERVED_HEADERS.iter().any(|r| r.eq_ignore_ascii_case(header.as_str())) => {
+                        problems.push(format!("{at}: headers can't override {name}"));
+                    }
+                    Ok(_) => {}
+                    Err(_) => problems.push(format!("{at}: headers has an invalid name {name:?}")),
+                }
+                if value.parse::<HeaderValue>().is_err() {
+                    problems.push(format!("{at}: headers has an invalid value for {name}"));
+                }
+            }
+            if let Err(e) = Relabeler::new(&d.relabel_configs) {
+                problems.push(format!("{at}: {e}"));
+            }
+        }
+        if !problems.is_empty() {
+            bail!("Invalid configuration:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
+impl DestinationConfig {
+    /// A destination set up from the environment alone, with its own address, credentials,
+    /// headers and so on, falling back to the unprefixed variables for anything not given.
+    fn from_env(name: &str, lookup: &dyn Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let env = match name {
+            DEFAULT_DESTINATION => EnvScope::new(lookup),
+            _ => EnvScope::new(lookup).for_destination(name, true),
+        };
+        let mut destination = DestinationConfig {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        destination.apply_env(&env)?;
+        // a shared WAL_DIR gets a subdirectory per destination
+        if let Some(wal) = destination.wal.as_mut() {
+            if name != DEFAULT_DESTINATION && !env.is_scoped("WAL_DIR") {
+                wal.dir.push(name);
+            }
+        }
+        Ok(destination)
+    }
+
+    /// Where write requests are posted.
+    pub fn write_url(&self) -> String {
+        let path = match (self.path.as_ref(), self.sigv4.as_ref()) {
+            (Some(path), _) => path.as_str(),
+            (None, Some(_)) => DEFAULT_SIGV4_WRITE_PATH,
+            (None, None) => DEFAULT_WRITE_PATH,
+        };
+        format!("{}{path}", self.addr.trim_end_matches('/'))
+    }
+
+    fn apply_env(&mut self, env: &EnvScope) -> anyhow::Result<()> {
+        env.set("PROM_WRITE_ADDR", &mut self.addr)?;
+        if let Some(path) = env.var("REMOTE_WRITE_PATH") {
+            self.path = Some(path);
+        }
+        env.set("REMOTE_WRITE_PROTOCOL", &mut self.protocol)?;
+        env.set("REMOTE_WRITE_TIMEOUT_SECS", &mut self.timeout_secs)?;
+        env.set("REMOTE_WRITE_CONNECT_TIMEOUT_SECS", &mut self.connect_timeout_secs)?;
+        if let Some(proxy) = env.var("REMOTE_WRITE_PROXY_URL") {
+            self.proxy_url = Some(proxy);
+        }
+        let mut tls = self.tls.take().unwrap_or_default();
+        if let Some(path) = env.var("REMOTE_WRITE_CA_FILE") {
+            tls.ca_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env.var("REMOTE_WRITE_CERT_FILE") {
+            tls.cert_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env.var("REMOTE_WRITE_KEY_FILE") {
+            tls.key_file = Some(PathBuf::from(path));
+        }
+        if let Some(name) = env.var("REMOTE_WRITE_SERVER_NAME") {
+            tls.server_name = Some(name);
+        }
+        if tls != ClientTlsConfig::default() {
+            self.tls = Some(tls);
+        }
+        env.set("FLUSH_INTERVAL_SECS", &mut self.flush_interval_secs)?;
+        env.set("FLUSH_MAX_SAMPLES", &mut self.flush_max_samples)?;
+        env.set("QUEUE_CAPACITY", &mut self.queue_capacity)?;
+        env.set("REMOTE_WRITE_MAX_ATTEMPTS", &mut self.retry.max_attempts)?;
+        env.set("REMOTE_WRITE_MIN_BACKOFF_MS", &mut self.retry.min_backoff_ms)?;
+        env.set("REMOTE_WRITE_MAX_BACKOFF_MS", &mut self.retry.max_backoff_ms)?;
+        env.set("REMOTE_WRITE_RETRY_MAX_AGE_SECS", &mut self.retry.max_age_secs)?;
+        // the wal is opt-in, it only exists once there's a directory for it
+        if let Some(dir) = env.var("WAL_DIR") {
+            self.wal = Some(WalConfig {
+                dir: PathBuf::from(dir),
+                max_bytes: default_wal_max_bytes(),
+                max_age_secs: default_wal_max_age_secs(),
+            });
+        }
+        if let Some(wal) = self.wal.as_mut() {
+            env.set("WAL_MAX_BYTES", &mut wal.max_bytes)?;
+            env.set("WAL_MAX_AGE_SECS", &mut wal.max_age_secs)?;
+        }
+        // a credential from the environment replaces whichever one the file has, rather than
+        // being sent alongside it
+        let basic_auth = env.var("PROM_USERNAME").map(|username| BasicAuthConfig {
+            username,
+            password: env.var("PROM_PASSWORD"),
+            password_file: env.var("PROM_PASSWORD_FILE").map(PathBuf::from),
+        });
+        let bearer_token = env.var("PROM_BEARER_TOKEN");
+        let bearer_token_file = env.var("PROM_BEARER_TOKEN_FILE").map(PathBuf::from);
+        let sigv4 = env.var("SIGV4_REGION").map(|region| SigV4Config {
+            region,
+            service: default_sigv4_service(),
+        });
+        if basic_auth.is_some() || bearer_token.is_some() || bearer_token_file.is_some() || sigv4.is_some() {
+            self.basic_auth = basic_auth;
+            self.bearer_token = bearer_token;
+            self.bearer_token_file = bearer_token_file;
+            self.sigv4 = sigv4;
+        }
+        if let Some(sigv4) = self.sigv4.as_mut() {
+            env.set("SIGV4_SERVICE", &mut sigv4.service)?;
+        }
+        // TENANT_MAP=111111111111=unit-a,222222222222=unit-b routes whole accounts; anything more
+        // specific needs the config file
+        if let Some(map) = env.var("TENANT_MAP") {
+            let tenants = self.tenants.get_or_insert_with(TenantConfig::default);
+            for pair in map.split(',').filter(|p| !p.trim().is_empty()) {
+                let (account_id, tenant) = pair
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("{}TENANT_MAP: {pair:?} isn't account_id=tenant", env.prefix))?;
+                tenants.rules.push(TenantRule {
+                    account_id: account_id.trim().to_string(),
+                    region: None,
+                    metric_stream_name: None,
+                    tenant: tenant.trim().to_string(),
+                });
+            }
+        }
+        if let Some(tenants) = self.tenants.as_mut() {
+            env.set("TENANT_DEFAULT", &mut tenants.default)?;
+        }
+        // REMOTE_WRITE_HEADERS=X-Scope-OrgID=tenant,X-Other=value
+        if let Some(headers) = env.var("REMOTE_WRITE_HEADERS") {
+            for pair in headers.split(',').filter(|p| !p.trim().is_empty()) {
+                let (name, value) = pair
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("{}REMOTE_WRITE_HEADERS: {pair:?} isn't name=value", env.prefix))?;
+                self.headers.insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FilterConfig {
+    pub fn allows(&self, metric: &CloudWatchMetric) -> bool {
+        let matches = |list: &[String]| list.iter().any(|n| n.eq_ignore_ascii_case(&metric.namespace));
+        (self.include_namespaces.is_empty() || matches(&self.include_namespaces))
+            && !matches(&self.exclude_namespaces)
+    }
+}
+
+/// The process environment, as a lookup.
+pub fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+pub fn read_file(path: &Path) -> anyhow::Result<String> {
+    fs::read_to_string(path).map_err(|e| anyhow!("Couldn't read config file {path:?}: {e}"))
+}
+
+/// Looks variables up for one destination.  A destination's own `<NAME>_<KEY>` wins; with
+/// `fallback` the plain `<KEY>` is used after that, so shared settings only need giving once.
+#[derive(Clone)]
+struct EnvScope<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    prefix: String,
+    fallback: bool,
+}
+
+impl<'a> EnvScope<'a> {
+    /// The plain variables, read through `lookup`.
+    fn new(lookup: &'a dyn Fn(&str) -> Option<String>) -> Self {
+        EnvScope {
+            lookup,
+            prefix: String::new(),
+            fallback: true,
+        }
+    }
+
+    fn for_destination(&self, name: &str, fallback: bool) -> Self {
+        EnvScope {
+            lookup: self.lookup,
+            prefix: format!("{}_", name.to_uppercase().replace('-', "_")),
+            fallback,
+        }
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        if !self.prefix.is_empty() {
+            if let Some(v) = (self.lookup)(&format!("{}{key}", self.prefix)) {
+                return Some(v);
+            }
+        }
+        if self.fallback {
+            return (self.lookup)(key);
+        }
+        None
+    }
+
+    fn is_scoped(&self, key: &str) -> bool {
+        !self.prefix.is_empty() && (self.lookup)(&format!("{}{key}", self.prefix)).is_some()
+    }
+
+    /// Overwrites `field` if the variable is set, and complains if it doesn't parse.
+    fn set<T: FromStr>(&self, key: &str, field: &mut T) -> anyhow::Result<()> {
+        if let Some(v) = self.var(key) {
+            *field = v
+                .parse()
+                .map_err(|_| anyhow!("{}{key} has an invalid value {v:?}", self.prefix))?;
+        }
+        Ok(())
+    }
+}
+
+/// Interpolates every string in a parsed document, and returns whether any of them changed.
+/// Comments are never looked at, and whatever a variable holds only ever makes up one value.
+fn interpolate_values(
+    value: &mut serde_yaml::Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<bool> {
+    use serde_yaml::Value;
+    let mut changed = false;
+    match value {
+        Value::String(s) => {
+            let interpolated = interpolate(s, lookup)?;
+            if interpolated != *s {
+                *value = scalar_from_env(interpolated);
+                changed = true;
+            }
+        }
+        Value::Sequence(values) => {
+            for v in values.iter_mut() {
+                changed |= interpolate_values(v, lookup)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for v in mapping.values_mut() {
+                changed |= interpolate_values(v, lookup)?;
+            }
+        }
+        Value::Tagged(tagged) => changed = interpolate_values(&mut tagged.value, lookup)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(changed)
+}
+
+/// A number or boolean written the way yaml would write it is taken as one, so that e.g.
+/// `timeout_secs: ${TIMEOUT}` works; anything else stays exactly the string it was.
+fn scalar_from_env(s: String) -> serde_yaml::Value {
+    use serde_yaml::Value;
+    match serde_yaml::from_str::<Value>(&s) {
+        Ok(v @ (Value::Number(_) | Value::Bool(_)))
+            if serde_yaml::to_string(&v).is_ok_and(|written| written.trim_end() == s) =>
+        {
+            v
+        }
+        _ => Value::String(s),
+    }
+}
+
+/// Replaces `${VAR}` and `${VAR:-default}`; `$$` is a literal `$`.  As in the shell, the default
+/// is used when the variable is unset or empty.  A variable that isn't set and has no default is
+/// an error rather than an empty string, since it's almost always a typo.
+fn interpolate(raw: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("${") {
+            let end = r
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated ${{ in {:?}", rest.lines().next().unwrap_or("")))?;
+            let expr = &r[..end];
+            let (name, default) = match expr.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expr, None),
+            };
+            let value = match (lookup(name), default) {
+                (Some(v), Some(default)) if v.is_empty() => Some(default.to_string()),
+                (v, default) => v.or(default.map(String::from)),
+            };
+            match value {
+                Some(v) => out.push_str(&v),
+                None => bail!("environment variable {name} is not set"),
+            }
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[test]
+fn test_interpolate() {
+    let lookup = |name: &str| match name {
+        "TOKEN" => Some("s3cret".to_string()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    };
+    assert_eq!(
+        interpolate("a: ${TOKEN}\nb: ${MISSING:-x}\nc: $$5 $HOME", lookup).unwrap(),
+        "a: s3cret\nb: x\nc: $5 $HOME"
+    );
+    assert!(interpolate("a: ${MISSING}", lookup).is_err());
+    // an empty variable takes the default, but is still set
+    assert_eq!(interpolate("a: ${EMPTY:-x}\nb: '${EMPTY}'", lookup).unwrap(), "a: x\nb: ''");
+
+    let lookup = |name: &str| match name {
+        "TOKEN" => Some("s3cret: # not a comment".to_string()),
+        "TIMEOUT" => Some("20".to_string()),
+        "TENANT" => Some("012".to_string()),
+        _ => None,
+    };
+    let config = Config::parse_with(
+        r#"
+# ${UNSET} in a comment is left alone
+destinations:
+  - name: mimir
+    addr: ${ADDR:-http://mimir:8080}
+    bearer_token: ${TOKEN}
+    timeout_secs: ${TIMEOUT}
+    tenants:
+      default: ${TENANT}
+"#,
+        lookup,
+    )
+    .unwrap();
+    let mimir = &config.destinations[0];
+    assert_eq!(mimir.addr, "http://mimir:8080");
+    assert_eq!(mimir.bearer_token.as_deref(), Some("s3cret: # not a comment"));
+    assert_eq!(mimir.timeout_secs, 20);
+    assert_eq!(mimir.tenants.as_ref().unwrap().default, "012");
+    assert!(Config::parse_with("listen: ${UNSET}", lookup).is_err());
+}
+
+#[test]
+fn test_env_destinations_have_their_own_auth_and_headers() {
+    let lookup = |name: &str| {
+        let value = match name {
+            "PROM_WRITE_DESTINATIONS" => "envdest-a,envdest-b",
+            "ENVDEST_A_PROM_WRITE_ADDR" => "http://a:8080",
+            "ENVDEST_A_PROM_USERNAME" => "alice",
+            "ENVDEST_A_PROM_PASSWORD" => "s3cret",
+            "ENVDEST_A_REMOTE_WRITE_HEADERS" => "X-Team=a",
+            "ENVDEST_B_PROM_WRITE_ADDR" => "http://b:8080",
+            "ENVDEST_B_PROM_BEARER_TOKEN" => "t0ken",
+            "ENVDEST_B_REMOTE_WRITE_HEADERS" => "X-Team=b",
+            "FLUSH_INTERVAL_SECS" => "5",
+            _ => return None,
+        };
+        Some(value.to_string())
+    };
+    let mut config = Config::default();
+    config.apply_env(&lookup).unwrap();
+    let [a, b] = &config.destinations[..] else {
+        panic!("expected two destinations, got {:?}", config.destinations);
+    };
+
+    assert_eq!(a.addr, "http://a:8080");
+    let basic = a.basic_auth.as_ref().unwrap();
+    assert_eq!((basic.username.as_str(), basic.password.as_deref()), ("alice", Some("s3cret")));
+    assert_eq!(a.headers.get("X-Team").map(String::as_str), Some("a"));
+    assert_eq!(b.addr, "http://b:8080");
+    assert!(b.basic_auth.is_none());
+    assert_eq!(b.bearer_token.as_deref(), Some("t0ken"));
+    assert_eq!(b.headers.get("X-Team").map(String::as_str), Some("b"));
+    // and the shared settings where they have none of their own
+    assert_eq!((a.flush_interval_secs, b.flush_interval_secs), (5, 5));
+}
+
+#[test]
+fn test_example_config_loads() {
+    let config = Config::parse_with(include_str!("../config.example.yaml"), env_var).unwrap();
+    config.validate().unwrap();
+    let url = |name: &str| config.destinations.iter().find(|d| d.name == name).unwrap().write_url();
+    assert_eq!(url("mimir"), "http://mimir-distributor.mimir:8080/api/v1/push");
+    assert!(url("staging").ends_with(":8428/api/v1/write"));
+    assert!(url("amp").ends_with("/workspaces/ws-00000000-0000-0000-0000-000000000000/api/v1/remote_write"));
+}
+
+#[test]
+fn test_parse_and_validate() {
+    let config = Config::parse_with(
+        r#"
+listen: 127.0.0.1:8080
+metric_stream_format: opentelemetry1.0
+destinations:
+  - name: mimir
+    addr: http://mimir:8080
+    protocol: "2.0"
+    retry:
+      max_attempts: 3
+  - name: staging
+    addr: http://victoria:8428
+    wal:
+      dir: /var/lib/firehose/staging
+"#,
+        env_var,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.metric_stream_format, MetricStreamFormat::OpenTelemetry1);
+    assert_eq!(config.destinations[0].protocol, RemoteWriteProtocol::V2);
+    assert_eq!(config.destinations[0].retry.max_attempts, 3);
+    assert_eq!(config.destinations[0].retry.max_backoff_ms, DEFAULT_RETRY_MAX_BACKOFF_MS);
+    assert_eq!(config.destinations[1].wal.as_ref().unwrap().max_bytes, DEFAULT_WAL_MAX_BYTES);
+
+    assert!(Config::parse_with("listen: [1, 2]", env_var).is_err());
+    assert!(Config::parse_with("lisen: 0.0.0.0:3000", env_var).is_err());
+    let broken = Config::parse_with("listen: nowhere\ndestinations:\n  - name: a\n  - name: a\n    addr: x", env_var).unwrap();
+    let problems = broken.validate().unwrap_err().to_string();
+    assert!(problems.contains("listen"));
+    assert!(problems.contains("addr is missing"));
+    assert!(problems.contains("used more than once"));
+
+    let https_only = Config::parse_with(
+        "listen: ''\ntls:\n  cert_file: /tls/tls.crt\n  key_file: /tls/tls.key\ndestinations:\n  - addr: http://a",
+        env_var,
+    )
+    .unwrap();
+    https_only.validate().unwrap();
+    assert_eq!(https_only.tls.unwrap().listen, DEFAULT_TLS_LISTEN_ADDR);
+    let nothing = Config::parse_with("listen: ''\ndestinations:\n  - addr: http://a", env_var).unwrap();
+    assert!(nothing.validate().is_err());
+
+    let auth = Config::parse_with(
+        r#"
+destinations:
+  - addr: http://mimir:8080
+    bearer_token: abc
+    bearer_token_file: /token
+    headers:
+      X-Scope-OrgID: tenant-1
+      content-type: text/plain
+"#,
+        env_var,
+    )
+    .unwrap();
+    let problems = auth.validate().unwrap_err().to_string();
+    assert!(problems.contains("can't both be set"));
+    assert!(problems.contains("can't override content-type"));
+    assert!(!problems.contains("X-Scope-OrgID"));
+}
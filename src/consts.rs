@@ -1 +1,31 @@
 pub const PROM_NAMESPACE: &str = "firehose";
+pub const DEFAULT_NAME_TEMPLATE: &str = "{prefix}_{namespace}_{metric}_{unit}_{stat}";
+pub const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:3000";
+pub const DEFAULT_TLS_LISTEN_ADDR: &str = "0.0.0.0:3443";
+pub const DEFAULT_CONFIG_WATCH_INTERVAL_SECS: u64 = 10;
+pub const FIREHOSE_REQUEST_ID_HEADER: &str = "x-amz-firehose-request-id";
+// matches the largest buffer size firehose will send to an http endpoint
+pub const DEFAULT_MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+pub const DEFAULT_DESTINATION: &str = "default";
+// prometheus' own receiver, victoriametrics and most others; amazon managed service for
+// prometheus has its own
+pub const DEFAULT_WRITE_PATH: &str = "/api/v1/write";
+pub const DEFAULT_SIGV4_WRITE_PATH: &str = "/api/v1/remote_write";
+pub const DEFAULT_REMOTE_WRITE_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_FLUSH_MAX_SAMPLES: usize = 10_000;
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1_000;
+// prometheus' own remote write defaults, with a bounded retry window since firehose has already
+// been told the data arrived
+pub const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 10;
+pub const DEFAULT_RETRY_MIN_BACKOFF_MS: u64 = 30;
+pub const DEFAULT_RETRY_MAX_BACKOFF_MS: u64 = 5_000;
+pub const DEFAULT_RETRY_MAX_AGE_SECS: u64 = 300;
+// what mimir and cortex call the data of a single-tenant setup
+pub const DEFAULT_TENANT: &str = "anonymous";
+pub const DEFAULT_TENANT_HEADER: &str = "X-Scope-OrgID";
+// amazon managed service for prometheus
+pub const DEFAULT_SIGV4_SERVICE: &str = "aps";
+pub const DEFAULT_WAL_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+pub const DEFAULT_WAL_MAX_AGE_SECS: u64 = 24 * 60 * 60;
@@ -0,0 +1,468 @@
+use crate::auth::AuthSettings;
+use crate::batch::MetricBatch;
+use crate::client::{build_client, request_url};
+use crate::config::DestinationConfig;
+use crate::relabel::Relabeler;
+use crate::prometheus::{DESTINATION_DROPPED_BATCHES, FLUSH_BATCH_SIZE, FLUSH_DURATION, FLUSH_QUEUE_DEPTH};
+use crate::remote_write::RemoteWriteProtocol;
+use crate::sigv4::SigV4Settings;
+use crate::tenant::{Tenant, TenantRouting};
+use crate::retry::{send_with_retry, RetrySettings, SendOutcome};
+use crate::wal::{Wal, WalSettings};
+use reqwest::Client;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+
+pub type FlushSender = mpsc::Sender<MetricBatch>;
+
+/// One remote-write destination's settings.  Every destination gets its own queue, flusher
+/// task, http client, retry budget and wal, so a slow one can't hold up the others.  Clones
+/// share the client and with it the connection pool.
+#[derive(Debug, Clone)]
+pub struct FlusherSettings {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    pub(crate) protocol: RemoteWriteProtocol,
+    pub(crate) interval: Duration,
+    pub(crate) max_samples: usize,
+    pub(crate) queue_capacity: usize,
+    pub(crate) retry: RetrySettings,
+    pub(crate) wal: Option<WalSettings>,
+    pub(crate) auth: AuthSettings,
+    pub(crate) sigv4: Option<SigV4Settings>,
+    pub(crate) tenants: Option<TenantRouting>,
+    pub(crate) relabel: Relabeler,
+    pub(crate) client: Client,
+}
+
+impl FlusherSettings {
+    /// Fails if the http client can't be built, e.g. for a missing certificate, or the
+    /// relabeling doesn't compile.
+    pub fn from_config(config: &DestinationConfig) -> anyhow::Result<Self> {
+        Ok(FlusherSettings {
+            name: config.name.clone(),
+            url: request_url(config)?,
+            protocol: config.protocol,
+            interval: Duration::from_secs(config.flush_interval_secs),
+            max_samples: config.flush_max_samples,
+            queue_capacity: config.queue_capacity,
+            retry: RetrySettings::from_config(&config.retry),
+            wal: config
+                .wal
+                .as_ref()
+                .map(|wal| WalSettings::from_config(&config.name, wal)),
+            auth: AuthSettings::from_config(config),
+            sigv4: config.sigv4.as_ref().map(SigV4Settings::from_config),
+            tenants: config.tenants.as_ref().map(TenantRouting::from_config).transpose()?,
+            relabel: Relabeler::new(&config.relabel_configs)?,
+            client: build_client(config)?,
+        })
+    }
+
+    /// One batch per request to send, after this destination's relabeling: per tenant if the
+    /// destination has tenants, otherwise the whole thing.
+    pub fn split(&self, batch: MetricBatch) -> Vec<(Option<Tenant>, MetricBatch)> {
+        let batch = batch.relabel(&self.relabel);
+        match self.tenants.as_ref() {
+            Some(tenants) => tenants
+                .split(batch)
+                .into_iter()
+                .map(|(id, batch)| {
+                    let tenant = Tenant {
+                        header: tenants.header.clone(),
+                        id,
+                    };
+                    (Some(tenant), batch)
+                })
+                .collect(),
+            None => vec![(None, batch)],
+        }
+    }
+}
+
+/// The ingest side of a running flusher, along with the config it was started from.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    pub(crate) name: String,
+    pub(crate) sender: FlushSender,
+    pub(crate) config: DestinationConfig,
+}
+
+/// Starts a destination's flusher, which sends what's queued every `interval` or once
+/// `max_samples` are waiting, and stops when every `Destination` clone is gone.  It sends without
+/// a wal until `previous` has drained, so that the two never share one.
+pub fn spawn_flusher(
+    settings: FlusherSettings,
+    config: &DestinationConfig,
+    previous: Option<JoinHandle<()>>,
+) -> (Destination, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel(settings.queue_capacity);
+    let destination = Destination {
+        name: settings.name.clone(),
+        sender: tx,
+        config: config.clone(),
+    };
+    let handle = tokio::spawn(run_flusher(settings, rx, previous));
+    (destination, handle)
+}
+
+/// Queues a batch for the flusher without waiting.
+pub fn enqueue(destination: &Destination, batch: MetricBatch) -> anyhow::Result<()> {
+    let sender = &destination.sender;
+    match sender.try_send(batch) {
+        Ok(_) => {
+            FLUSH_QUEUE_DEPTH
+                .with_label_values(&[&destination.name])
+                .set((sender.max_capacity() - sender.capacity()) as f64);
+            Ok(())
+        }
+        Err(TrySendError::Full(_)) => bail!("flush queue for {} is full", destination.name),
+        Err(TrySendError::Closed(_)) => bail!("flusher for {} is not running", destination.name),
+    }
+}
+
+/// Hands a batch to every destination.  A destination whose queue is full only loses the batch
+/// for itself; the error is handed back, so that firehose is told to retry later, only if no
+/// destination took it.
+pub fn fan_out(destinations: &[Destination], batch: MetricBatch) -> anyhow::Result<()> {
+    let Some((last, rest)) = destinations.split_last() else {
+        bail!("no remote write destinations are configured");
+    };
+    let mut errors: Vec<String> = vec![];
+    for destination in rest {
+        if let Err(e) = enqueue(destination, batch.clone()) {
+            errors.push(e.to_string());
+            DESTINATION_DROPPED_BATCHES.with_label_values(&[&destination.name]).inc();
+        }
+    }
+    if let Err(e) = enqueue(last, batch) {
+        errors.push(e.to_string());
+        DESTINATION_DROPPED_BATCHES.with_label_values(&[&last.name]).inc();
+    }
+    if errors.len() == destinations.len() {
+        bail!("{}", errors.join(", "));
+    }
+    for e in errors {
+        warn!("Dropping batch: {e}");
+    }
+    Ok(())
+}
+
+struct Flusher {
+    settings: FlusherSettings,
+    wal: Option<WalReplay>,
+}
+
+/// A wal and the task that replays it.  Batches are appended and the flusher moves straight on,
+/// so an unhealthy receiver is waited out on disk rather than in the flush queue.
+struct WalReplay {
+    wal: Arc<Mutex<Wal>>,
+    wake: Arc<Notify>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+async fn run_flusher(
+    settings: FlusherSettings,
+    mut rx: mpsc::Receiver<MetricBatch>,
+    previous: Option<JoinHandle<()>>,
+) {
+    let mut ticker = tokio::time::interval(settings.interval);
+    let mut flusher = Flusher::new(settings);
+    let mut handed_over = previous.is_none();
+    if handed_over {
+        flusher.open_wal();
+    }
+    let previous = async move {
+        if let Some(previous) = previous {
+            let _ = previous.await;
+        }
+    };
+    tokio::pin!(previous);
+    let mut pending = MetricBatch::default();
+    loop {
+        tokio::select! {
+            _ = &mut previous, if !handed_over => {
+                handed_over = true;
+                flusher.open_wal();
+                continue;
+            }
+            received = rx.recv() => {
+                match received {
+                    Some(batch) => {
+                        FLUSH_QUEUE_DEPTH
+                            .with_label_values(&[&flusher.settings.name])
+                            .set(rx.len() as f64);
+                        pending.merge(batch);
+                        if pending.samples() < flusher.settings.max_samples {
+                            continue;
+                        }
+                    }
+                    None => {
+                        // every sender is gone, so send what's left and stop
+                        flusher.flush(std::mem::take(&mut pending)).await;
+                        flusher.stop().await;
+                        return;
+                    }
+                }
+            }
+            _ = ticker.tick() => {}
+        }
+        flusher.flush(std::mem::take(&mut pending)).await;
+        ticker.reset();
+    }
+}
+
+impl Flusher {
+    fn new(settings: FlusherSettings) -> Self {
+        Flusher { settings, wal: None }
+    }
+
+    /// Opens the wal, if the destination has one, and starts replaying it.
+    fn open_wal(&mut self) {
+        let settings = &self.settings;
+        self.wal = match settings.wal.clone().map(Wal::open) {
+            Some(Ok(wal)) => {
+                let wal = Arc::new(Mutex::new(wal));
+                let wake = Arc::new(Notify::new());
+                let (stop, stopped) = watch::channel(false);
+                let task = tokio::spawn(replay_wal(settings.clone(), Arc::clone(&wal), Arc::clone(&wake), stopped));
+                Some(WalReplay { wal, wake, stop, task })
+            }
+            Some(Err(e)) => {
+                error!("Couldn't open wal for {}, batches will only be held in memory: {e}", settings.name);
+                None
+            }
+            None => None,
+        };
+    }
+
+    /// Gives the wal one last pass at the receiver, so that whatever it can take now isn't left
+    /// waiting for the next flusher.
+    async fn stop(self) {
+        if let Some(replay) = self.wal {
+            let _ = replay.stop.send(true);
+            let _ = replay.task.await;
+        }
+        info!("Flusher for {} stopped.", self.settings.name);
+    }
+
+    async fn flush(&mut self, batch: MetricBatch) {
+        if batch.is_empty() {
+            return;
+        }
+        let start = Instant::now();
+        FLUSH_BATCH_SIZE
+            .with_label_values(&[&self.settings.name])
+            .observe(batch.samples() as f64);
+        for (tenant, batch) in self.settings.split(batch) {
+            match self.settings.protocol.encode(batch) {
+                Ok(body) => self.send(body, tenant).await,
+                Err(e) => {
+                    error!("Failed to encode write request for {}: {e}", self.settings.name);
+                }
+            }
+        }
+        FLUSH_DURATION
+            .with_label_values(&[&self.settings.name])
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    /// With a wal, a batch is only written to disk here; the replay task sends it, strictly
+    /// after anything older that is still waiting.
+    async fn send(&mut self, body: Vec<u8>, tenant: Option<Tenant>) {
+        if let Some(replay) = self.wal.as_ref() {
+            let appended = replay
+                .wal
+                .lock()
+                .unwrap()
+                .append(&body, self.settings.protocol, tenant.as_ref());
+            match appended {
+                Ok(_) => {
+                    replay.wake.notify_one();
+                    return;
+                }
+                Err(e) => {
+                    error!("Couldn't write batch to wal for {}, sending without it: {e}", self.settings.name);
+                }
+            }
+        }
+        let settings = &self.settings;
+        if send_with_retry(settings, body.into(), settings.protocol, tenant.as_ref()).await == SendOutcome::Delivered {
+            debug!("succeeded on push to {}", settings.name);
+        }
+    }
+}
+
+/// Replays the wal whenever something is appended, and backs off between passes while the
+/// receiver is unhealthy.  Once told to stop it makes one more pass and returns.
+async fn replay_wal(settings: FlusherSettings, wal: Arc<Mutex<Wal>>, wake: Arc<Notify>, mut stop: watch::Receiver<bool>) {
+    let mut failed_passes: u32 = 0;
+    loop {
+        let caught_up = replay_segments(&settings, &wal).await;
+        if *stop.borrow() {
+            return;
+        }
+        if caught_up {
+            failed_passes = 0;
+            tokio::select! {
+                _ = wake.notified() => {}
+                _ = stop.changed() => {}
+            }
+        } else {
+            let delay = settings.retry.backoff(failed_passes);
+            failed_passes = failed_passes.saturating_add(1);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = stop.changed() => {}
+            }
+        }
+    }
+}
+
+/// Sends wal segments oldest first, stopping at the first one the receiver can't take yet so
+/// that the rest stay in order for the next pass.  Returns whether the wal was emptied.
+async fn replay_segments(settings: &FlusherSettings, wal: &Mutex<Wal>) -> bool {
+    let listed = wal.lock().unwrap().segments();
+    let segments = match listed {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't list wal segments: {e}");
+            return false;
+        }
+    };
+    for segment in segments {
+        let read = wal.lock().unwrap().read(&segment);
+        let (body, tenant) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                // most likely dropped by retention since it was listed
+                warn!("Couldn't read wal segment {:?}, skipping it: {e}", segment.path);
+                wal.lock().unwrap().remove(&segment);
+                continue;
+            }
+        };
+        match send_with_retry(settings, body.into(), segment.protocol, tenant.as_ref()).await {
+            SendOutcome::Delivered | SendOutcome::Rejected => wal.lock().unwrap().remove(&segment),
+            SendOutcome::GaveUp => {
+                warn!(
+                    "Remote write to {} is unhealthy, leaving {:?} in the wal for later.",
+                    settings.name, segment.path
+                );
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[test]
+fn test_fan_out_skips_full_destinations() {
+    let destination = |name: &str| {
+        let (sender, rx) = mpsc::channel(1);
+        let destination = Destination {
+            name: name.to_string(),
+            sender,
+            config: DestinationConfig::default(),
+        };
+        (destination, rx)
+    };
+    let (healthy, mut healthy_rx) = destination("healthy");
+    let (stuck, _stuck_rx) = destination("stuck");
+    stuck.sender.try_send(MetricBatch::default()).unwrap();
+    let destinations = vec![stuck.clone(), healthy];
+
+    fan_out(&destinations, MetricBatch::default()).unwrap();
+    assert!(healthy_rx.try_recv().is_ok());
+    assert_eq!(
+        DESTINATION_DROPPED_BATCHES.with_label_values(&["stuck"]).get(),
+        1.0
+    );
+    // only refusing firehose once nobody could take the batch
+    assert!(fan_out(&[stuck], MetricBatch::default()).is_err());
+}
+
+#[tokio::test]
+async fn test_flush_with_wal_does_not_wait_for_the_receiver() {
+    use crate::config::{RetryConfig, WalConfig};
+    use crate::testing::{cpu_metric, CaptureServer, TempDir};
+
+    let dir = TempDir::new("replay");
+    // a port with nothing behind it yet
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let wal = WalConfig {
+        dir: dir.path().to_path_buf(),
+        max_bytes: 1 << 20,
+        max_age_secs: 3600,
+    };
+    let settings = FlusherSettings::from_config(&DestinationConfig {
+        addr: format!("http://{addr}"),
+        retry: RetryConfig {
+            max_attempts: 1_000,
+            min_backoff_ms: 20,
+            max_backoff_ms: 100,
+            max_age_secs: 60,
+        },
+        wal: Some(wal.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+    let mut batch = MetricBatch::default();
+    batch.add(&cpu_metric(1.0), &Default::default(), &Default::default());
+
+    let mut flusher = Flusher::new(settings);
+    flusher.open_wal();
+    // while the receiver is down, a flush only lands in the wal
+    tokio::time::timeout(Duration::from_secs(2), flusher.flush(batch))
+        .await
+        .expect("flush waited for the receiver");
+
+    let server = CaptureServer::start_on(addr).await;
+    let received = server.wait_for(1).await;
+    assert!(!received[0].body.is_empty());
+
+    flusher.stop().await;
+    let wal = Wal::open(WalSettings::from_config("default", &wal)).unwrap();
+    assert!(wal.segments().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_replacement_flusher_sends_while_the_previous_drains() {
+    use crate::config::WalConfig;
+    use crate::testing::{cpu_metric, CaptureServer, TempDir};
+
+    let dir = TempDir::new("handover");
+    let server = CaptureServer::start().await;
+    let config = DestinationConfig {
+        addr: format!("http://{}", server.addr),
+        flush_interval_secs: 3600,
+        flush_max_samples: 1,
+        wal: Some(WalConfig {
+            dir: dir.join("wal"),
+            max_bytes: 1 << 20,
+            max_age_secs: 3600,
+        }),
+        ..Default::default()
+    };
+    let previous = tokio::spawn(std::future::pending());
+    let abort = previous.abort_handle();
+    let settings = FlusherSettings::from_config(&config).unwrap();
+    let (destination, handle) = spawn_flusher(settings, &config, Some(previous));
+    let mut batch = MetricBatch::default();
+    batch.add(&cpu_metric(1.0), &Default::default(), &Default::default());
+
+    // the previous flusher still holds the wal, yet batches go out
+    enqueue(&destination, batch.clone()).unwrap();
+    server.wait_for(1).await;
+    assert!(!dir.join("wal").exists());
+
+    abort.abort();
+    enqueue(&destination, batch).unwrap();
+    server.wait_for(2).await;
+    drop(destination);
+    handle.await.unwrap();
+    assert!(dir.join("wal").exists());
+}
@@ -1,123 +1,366 @@
+mod access_key;
+mod auth;
+mod batch;
+mod cli;
+mod client;
+mod config;
 mod consts;
+mod flusher;
+mod naming;
+mod otlp;
 mod prometheus;
+mod relabel;
+mod reload;
+mod remote_write;
+mod retry;
+mod sigv4;
+mod wal;
 pub(crate) mod structs;
+// the ListMetrics lookup; conversion no longer needs a fixed label set per metric
+#[allow(dead_code)]
 pub(crate) mod aws;
+mod tenant;
+#[cfg(test)]
+mod testing;
+mod tls;
 
 #[macro_use]
 extern crate tracing;
 #[macro_use]
 extern crate anyhow;
 
-use std::error::Error;
-use crate::prometheus::{push_firehose_metrics, record_metric, STREAMS_RECEIVED};
-use crate::structs::{AppState, FirehoseData};
+use crate::access_key::validate_access_key;
+use crate::batch::MetricBatch;
+use crate::flusher::fan_out;
+use crate::prometheus::{gather_self_metrics, RECORDS_FAILED, STREAMS_RECEIVED};
+use crate::consts::{DEFAULT_CONFIG_WATCH_INTERVAL_SECS, FIREHOSE_REQUEST_ID_HEADER};
+use crate::reload::Reloader;
+use crate::tls::load_tls;
+use crate::structs::{FirehoseData, FirehoseError, FirehoseResponse, MetricStreamFormat, Pipeline};
 use crate::structs::SharedState;
-use crate::structs::{CloudWatchMetric, Firehose, MetricUnit, MetricValue};
-use ::prometheus::core::Metric;
+use crate::structs::{CloudWatchMetric, Firehose};
 use axum::body::Bytes;
-use axum::extract::{Path, State};
-use axum::http::header::CONTENT_TYPE;
-use axum::http::{HeaderMap, StatusCode};
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
 use axum::routing::{get, post};
-use axum::{debug_handler, extract, Json, Router};
+use axum::{debug_handler, Router};
 use base64::prelude::*;
-use serde::Deserialize;
-use std::env;
+use clap::Parser;
+use crate::cli::{Cli, Command, LogFormat};
+use flate2::read::GzDecoder;
+use std::future::IntoFuture;
+use std::io::Read;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use base64::decode;
-use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+    let command = cli.command.clone().unwrap_or(Command::Serve);
+    // the offline commands print their results to stdout, so keep the logs out of the way
+    let to_stderr = !matches!(command, Command::Serve);
+    init_logging(cli.log_format, to_stderr);
+
+    let result = match command {
+        Command::Serve => serve(cli).await,
+        Command::Convert { file, format } => cli::convert(&cli, &file, format).await,
+        Command::Replay { files, destination } => cli::replay(&cli, &files, destination.as_deref()).await,
+        Command::CheckConfig => cli::check_config(&cli),
+    };
+    if let Err(e) = result {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn init_logging(format: LogFormat, to_stderr: bool) {
     let filter_layer =
         EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info")).unwrap();
-    tracing_subscriber::fmt()
+    let writer = match to_stderr {
+        true => BoxMakeWriter::new(std::io::stderr),
+        false => BoxMakeWriter::new(std::io::stdout),
+    };
+    let builder = tracing_subscriber::fmt()
         .with_env_filter(filter_layer)
+        .with_writer(writer)
         .with_file(true)
-        .with_line_number(true)
-        .init();
+        .with_line_number(true);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
 
+async fn serve(cli: Cli) -> anyhow::Result<()> {
     let shared_state = SharedState::default();
-    {
-        let mut state = shared_state.write().await;
-        *state = AppState::default();
+    let mut reloader = Reloader::new(cli.config, cli.listen, Arc::clone(&shared_state));
+    let config = reloader.load()?;
+    reloader.apply(&config).await?;
+    if shared_state.read().await.pipeline.access_keys.is_empty() {
+        warn!("No firehose access keys configured; accepting deliveries from anyone.");
     }
+    tokio::spawn(reloader.watch(Duration::from_secs(DEFAULT_CONFIG_WATCH_INTERVAL_SECS)));
 
-    let app = Router::new()
+    let app = router(Arc::clone(&shared_state), config.max_decompressed_bytes);
+
+    let mut listeners = JoinSet::new();
+    if !config.listen.is_empty() {
+        let listener = tokio::net::TcpListener::bind(&config.listen)
+            .await
+            .map_err(|e| anyhow!("Couldn't listen on {}: {e}", config.listen))?;
+        info!("Spawning axum listener on {}.", config.listen);
+        listeners.spawn(axum::serve(listener, app.clone()).into_future());
+    }
+    if let Some(tls) = config.tls.as_ref() {
+        let rustls = load_tls(tls, Duration::from_secs(DEFAULT_CONFIG_WATCH_INTERVAL_SECS)).await?;
+        let addr: SocketAddr = tls.listen.parse()?;
+        info!("Spawning https listener on {addr}.");
+        listeners.spawn(axum_server::bind_rustls(addr, rustls).serve(app.into_make_service()));
+    }
+    // either listener stopping is fatal, there's no point carrying on half deaf
+    match listeners.join_next().await {
+        Some(Ok(Ok(_))) => bail!("A listener stopped unexpectedly."),
+        Some(Ok(Err(e))) => bail!("A listener failed: {e}"),
+        Some(Err(e)) => bail!("A listener panicked: {e}"),
+        None => bail!("No listeners configured."),
+    }
+}
+
+/// Axum refuses bodies over 2 MB by default, well short of what firehose can buffer, so the
+/// limit is raised to what a delivery may decompress to.  It's fixed at startup; a reload can
+/// only lower the limit, which `get_firehose` checks.
+fn router(state: SharedState, body_limit: usize) -> Router {
+    Router::new()
         .route("/", post(get_firehose).put(get_firehose))
-        .with_state(Arc::clone(&shared_state));
+        .route("/metrics", get(get_metrics))
+        .layer(DefaultBodyLimit::max(body_limit))
+        .with_state(state)
+}
+
+async fn get_metrics() -> Result<([(HeaderName, &'static str); 1], String), StatusCode> {
+    match gather_self_metrics() {
+        Ok(text) => Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], text)),
+        Err(e) => {
+            error!("Couldn't gather self metrics: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    info!("Spawning axum listener.");
-    axum::serve(listener, app).await.unwrap();
-    loop {}
+/// Firehose gzips the whole request body when the delivery stream has GZIP content encoding
+/// turned on.  The limit stops a small compressed body from inflating into an enormous one.
+fn decompress_body(headers: &HeaderMap, body: Bytes, limit: usize) -> Result<Bytes, FirehoseError> {
+    let is_gzip = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("gzip"));
+    if !is_gzip {
+        if body.len() > limit {
+            return Err(FirehoseError::PayloadTooLarge(limit));
+        }
+        return Ok(body);
+    }
+    let mut decompressed: Vec<u8> = vec![];
+    GzDecoder::new(&body[..])
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| FirehoseError::InvalidGzip(e.to_string()))?;
+    if decompressed.len() > limit {
+        return Err(FirehoseError::PayloadTooLarge(limit));
+    }
+    Ok(Bytes::from(decompressed))
 }
 
-async fn decode_payloads(records: Vec<FirehoseData>) -> Result<String,Box<dyn Error>> {
-    let payload_message = records
+fn decode_payloads(records: Vec<FirehoseData>) -> Vec<Result<Vec<u8>, FirehoseError>> {
+    records
         .iter()
-        .map(|s| String::from_utf8(BASE64_STANDARD.decode(&s.data).unwrap()).unwrap())
-        .collect::<Vec<String>>()
-        .join("");
-    Ok(payload_message)
-}
-pub async fn convert_to_cloudmetric(input: &str) -> Result<CloudWatchMetric, StatusCode> {
-    let metric: CloudWatchMetric = match serde_json::from_str(input) {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Can't process metric: {e}");
-            return Err(StatusCode::BAD_REQUEST);
+        .map(|record| {
+            BASE64_STANDARD
+                .decode(&record.data)
+                .map_err(|e| FirehoseError::InvalidBase64(e.to_string()))
+        })
+        .collect()
+}
+
+/// Turns one decoded firehose record into metrics, according to the metric stream output format.
+/// Whatever could be salvaged from the record is added to `metrics` even when an error is
+/// returned, so that a single bad line doesn't throw away the rest of a json record.
+async fn decode_record(
+    record: &[u8],
+    format: MetricStreamFormat,
+    metrics: &mut Vec<CloudWatchMetric>,
+) -> Result<(), FirehoseError> {
+    let looks_like_json = record.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{');
+    let decoded = match format {
+        MetricStreamFormat::OpenTelemetry1 => otlp::v1::decode_records(record),
+        MetricStreamFormat::OpenTelemetry07 => otlp::v07::decode_records(record),
+        // json records are newline-delimited objects and protobuf records start with a varint,
+        // but a 123 byte protobuf message starts with `{` too.  So json that yields nothing at
+        // all gets a second chance as protobuf.
+        MetricStreamFormat::Auto if looks_like_json => {
+            let mut from_json: Vec<CloudWatchMetric> = vec![];
+            match decode_json_record(record, &mut from_json).await {
+                Err(e) if from_json.is_empty() => match otlp::decode_records_any_version(record) {
+                    Ok(decoded) => Ok(decoded),
+                    Err(_) => return Err(e),
+                },
+                result => {
+                    metrics.extend(from_json);
+                    return result;
+                }
+            }
         }
+        MetricStreamFormat::Auto => otlp::decode_records_any_version(record),
+        MetricStreamFormat::Json => return decode_json_record(record, metrics).await,
     };
-    Ok(metric)
+    metrics.extend(decoded.map_err(|e| FirehoseError::InvalidProtobuf(e.to_string()))?);
+    Ok(())
+}
+
+async fn decode_json_record(record: &[u8], metrics: &mut Vec<CloudWatchMetric>) -> Result<(), FirehoseError> {
+    let text = std::str::from_utf8(record).map_err(|e| FirehoseError::InvalidUtf8(e.to_string()))?;
+    convert_json_lines(text, metrics).await
+}
+
+async fn convert_json_lines(text: &str, metrics: &mut Vec<CloudWatchMetric>) -> Result<(), FirehoseError> {
+    let mut first_error: Option<FirehoseError> = None;
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        trace!("Processing {line}");
+        match convert_to_cloudmetric(line).await {
+            Ok(metric) => metrics.push(metric),
+            Err(e) => {
+                debug!("unable to decode cloudmetric: {e}");
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub async fn convert_to_cloudmetric(input: &str) -> Result<CloudWatchMetric, FirehoseError> {
+    serde_json::from_str(input).map_err(|e| FirehoseError::InvalidJson(e.to_string()))
 }
 
 #[debug_handler]
 async fn get_firehose(
     State(state): State<SharedState>,
     headers: HeaderMap,
-    Json(payload): Json<Firehose>,
-) -> Result<String, StatusCode> {
-    let mut payload_message: String = String::from("");
-
-    if let Some(records) = payload.records {
-        // although it's not beyond belief that amazon would send us malformed b64, it's unlikely,
-        // so I'm skipping error processing here for now
-        payload_message = decode_payloads(records).await.unwrap_or_default();
-    };
+    body: Bytes,
+) -> FirehoseResponse {
+    // firehose sends the request id as a header too, which is all we have if the body won't parse
+    let header_request_id = headers
+        .get(FIREHOSE_REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
 
-    if let Some(message) = payload.message {
-        payload_message = message;
+    // hold on to this delivery's pipeline, so a reload part way through doesn't affect it
+    let pipeline = state.read().await.pipeline.clone();
+    if let Err(e) = validate_access_key(&headers, &pipeline.access_keys) {
+        warn!("Rejecting firehose delivery: {e}");
+        return FirehoseResponse::error(header_request_id.unwrap_or_default(), e);
     }
 
-    for line in payload_message.lines() {
-        trace!("Processing {line}");
-        if let Ok(metric) = convert_to_cloudmetric(line).await {
-            if let Err(e) = record_metric(metric).await {
-                error!("Couldn't record_metric: {e}");
-                continue;
-            }
-        } else {
-            debug!("unable to decode cloudmetric");
+    let body = match decompress_body(&headers, body, pipeline.max_decompressed_bytes) {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Rejecting firehose delivery: {e}");
+            return FirehoseResponse::error(header_request_id.unwrap_or_default(), e);
         }
+    };
 
+    let payload: Firehose = match serde_json::from_slice(&body) {
+        Ok(p) => p,
+        Err(e) => {
+            let err = FirehoseError::InvalidJson(e.to_string());
+            warn!("Rejecting firehose delivery: {err}");
+            return FirehoseResponse::error(header_request_id.unwrap_or_default(), err);
+        }
+    };
+    let request_id = payload
+        .request_id
+        .clone()
+        .or(header_request_id)
+        .unwrap_or_default();
+
+    match process_firehose(payload, &pipeline, &request_id).await {
+        Ok(_) => FirehoseResponse::ok(request_id),
+        Err(e @ FirehoseError::PartialFailure { .. }) => {
+            warn!("Partially processed firehose delivery {request_id}: {e}");
+            FirehoseResponse::error(request_id, e)
+        }
+        Err(e) => {
+            error!("Failed to process firehose delivery {request_id}: {e}");
+            FirehoseResponse::error(request_id, e)
+        }
     }
+}
+
+async fn process_firehose(
+    payload: Firehose,
+    pipeline: &Pipeline,
+    request_id: &str,
+) -> Result<(), FirehoseError> {
+    let (metrics, failed, total) = decode_delivery(payload, pipeline.metric_stream_format, request_id).await;
     STREAMS_RECEIVED.with_label_values(&[]).inc();
-    match push_firehose_metrics().await {
-        Ok(_) => {
-            debug!("succeeded on push")
+    if total > 0 && failed == total {
+        return Err(FirehoseError::NothingDecoded { total });
+    }
+    let batch = MetricBatch::from_metrics(&metrics, &pipeline.naming, &pipeline.filters, &pipeline.relabel);
+    fan_out(&pipeline.destinations, batch).map_err(|e| FirehoseError::QueueFull(e.to_string()))?;
+    if failed > 0 {
+        return Err(FirehoseError::PartialFailure { failed, total });
+    }
+    Ok(())
+}
+
+/// Decodes every record in a delivery, skipping the ones that can't be.  Returns the metrics
+/// along with how many records failed out of how many there were.
+async fn decode_delivery(
+    payload: Firehose,
+    format: MetricStreamFormat,
+    request_id: &str,
+) -> (Vec<CloudWatchMetric>, usize, usize) {
+    let mut metrics: Vec<CloudWatchMetric> = vec![];
+    let mut failed: usize = 0;
+    let mut total: usize = 0;
+
+    if let Some(records) = payload.records {
+        for (idx, record) in decode_payloads(records).into_iter().enumerate() {
+            total += 1;
+            let result = match record {
+                Ok(record) => decode_record(&record, format, &mut metrics).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                failed += 1;
+                RECORDS_FAILED.with_label_values(&[e.reason()]).inc();
+                warn!("Skipping record {idx} of delivery {request_id}: {e}");
+            }
         }
-        Err(e) => {
-            error!("Failed to push metrics: {e}");
+    };
+
+    if let Some(message) = payload.message {
+        metrics.clear();
+        total = 1;
+        failed = 0;
+        if let Err(e) = convert_json_lines(&message, &mut metrics).await {
+            failed = 1;
+            RECORDS_FAILED.with_label_values(&[e.reason()]).inc();
+            warn!("Skipping part of message in delivery {request_id}: {e}");
         }
     }
-    Ok("".to_string())
+    (metrics, failed, total)
 }
 #[cfg(test)]
 use std::fs::File;
-use std::io::Read;
 
 
 #[tokio::test]
@@ -128,10 +371,184 @@ async fn test_convert_to_labels_values() {
     let payload = serde_json::from_str::<Firehose>(&data).unwrap();
     // begin: Json(payload): Json<Firehose>
     if let Some(records) = payload.records {
-        let payload_str = decode_payloads(records).await.unwrap();
-        for line in payload_str.lines() {
-            let cm = convert_to_cloudmetric(&line).await.unwrap();
-            println!("{:#?}", cm.dimensions.to_labels_values());
+        for record in decode_payloads(records) {
+            let mut metrics: Vec<CloudWatchMetric> = vec![];
+            decode_record(&record.unwrap(), MetricStreamFormat::Json, &mut metrics)
+                .await
+                .unwrap();
+            for cm in metrics {
+                println!("{:#?}", cm.dimensions.to_labels_values());
+            }
         }
     }
 }
+
+#[test]
+fn test_firehose_response_body() {
+    let ok = serde_json::to_value(FirehoseResponse::ok("abc".to_string())).unwrap();
+    assert_eq!(ok["requestId"], "abc");
+    assert!(ok["timestamp"].as_u64().unwrap() > 0);
+    assert!(ok.get("errorMessage").is_none());
+
+    let err = FirehoseResponse::error(
+        "abc".to_string(),
+        FirehoseError::InvalidBase64("bad".to_string()),
+    );
+    assert_eq!(err.status, axum::http::StatusCode::BAD_REQUEST);
+    let err = serde_json::to_value(err).unwrap();
+    assert!(err["errorMessage"].as_str().unwrap().contains("bad"));
+
+    // only a delivery that was partly forwarded is acknowledged
+    let partial = FirehoseResponse::error("abc".to_string(), FirehoseError::PartialFailure { failed: 1, total: 2 });
+    assert_eq!(partial.status, axum::http::StatusCode::OK);
+    let nothing = FirehoseResponse::error("abc".to_string(), FirehoseError::NothingDecoded { total: 2 });
+    assert_eq!(nothing.status, axum::http::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_delivery_with_no_decodable_records_is_rejected() {
+    let record = |data: &str| FirehoseData { data: data.to_string() };
+    let payload = |data: Vec<FirehoseData>| Firehose {
+        records: Some(data),
+        ..Default::default()
+    };
+    let pipeline = Pipeline::default();
+    let result = process_firehose(payload(vec![record("not base64!"), record("%%%")]), &pipeline, "abc").await;
+    assert!(matches!(result, Err(FirehoseError::NothingDecoded { total: 2 })));
+    // with one good record, it's the destinations that decide
+    let good = BASE64_STANDARD.encode("");
+    let result = process_firehose(payload(vec![record("not base64!"), record(&good)]), &pipeline, "abc").await;
+    assert!(matches!(result, Err(FirehoseError::QueueFull(_))));
+}
+
+#[test]
+fn test_decompress_body() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&[b'a'; 4096]).unwrap();
+    let compressed = Bytes::from(encoder.finish().unwrap());
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+
+    let body = decompress_body(&headers, compressed.clone(), 4096).unwrap();
+    assert_eq!(body.len(), 4096);
+    assert!(matches!(
+        decompress_body(&headers, compressed.clone(), 1024),
+        Err(FirehoseError::PayloadTooLarge(1024))
+    ));
+    // without the header the body is passed through untouched
+    let body = decompress_body(&HeaderMap::new(), compressed.clone(), 1024).unwrap();
+    assert_eq!(body, compressed);
+}
+
+#[tokio::test]
+async fn test_accepts_deliveries_over_two_megabytes() {
+    use crate::flusher::Destination;
+    use crate::structs::AppState;
+    use tokio::sync::RwLock;
+
+    let (sender, mut rx) = tokio::sync::mpsc::channel(1);
+    let pipeline = Pipeline {
+        max_decompressed_bytes: consts::DEFAULT_MAX_DECOMPRESSED_BYTES,
+        metric_stream_format: MetricStreamFormat::Json,
+        destinations: vec![Destination {
+            name: "test".to_string(),
+            sender,
+            config: Default::default(),
+        }],
+        ..Default::default()
+    };
+    let state: SharedState = Arc::new(RwLock::new(AppState {
+        pipeline: Arc::new(pipeline),
+        generation: 1,
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = router(state, consts::DEFAULT_MAX_DECOMPRESSED_BYTES);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    // an uncompressed delivery of about 4 MB, as firehose sends without gzip turned on
+    let line = r#"{"metric_stream_name":"all","account_id":"111111111111","region":"us-east-1","namespace":"AWS/EC2","metric_name":"CPUUtilization","dimensions":{"InstanceId":"i-0123"},"timestamp":1700000000000,"value":{"max":1.0,"min":1.0,"sum":1.0,"count":1.0},"unit":"Percent"}"#;
+    let record = format!("{line}\n").repeat(3 * 1024 * 1024 / line.len());
+    let body = serde_json::json!({
+        "requestId": "big",
+        "timestamp": 1700000000000u64,
+        "records": [{"data": BASE64_STANDARD.encode(record)}],
+    })
+    .to_string();
+    assert!(body.len() > 2 * 1024 * 1024);
+    let response = reqwest::Client::new()
+        .post(format!("http://{addr}/"))
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!rx.try_recv().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_auto_decodes_protobuf_that_starts_like_json() {
+    use crate::otlp::v1::{
+        any_value, AnyValue, ExportMetricsServiceRequest, KeyValue, Metric, ResourceMetrics, ScopeMetrics, Summary,
+        SummaryDataPoint,
+    };
+    use prost::Message;
+
+    fn kv(key: &str, value: any_value::Value) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue { value: Some(value) }),
+        }
+    }
+    let s = |v: &str| any_value::Value::StringValue(v.to_string());
+
+    // pad the description until the message is 123 bytes, whose length prefix is `{`
+    let request = |description: &str| ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: None,
+            scope_metrics: vec![ScopeMetrics {
+                metrics: vec![Metric {
+                    // nothing for the 0.7 decoder to fall back on
+                    name: String::new(),
+                    description: description.to_string(),
+                    unit: "Percent".to_string(),
+                    summary: Some(Summary {
+                        data_points: vec![SummaryDataPoint {
+                            attributes: vec![
+                                kv("Namespace", s("AWS/EC2")),
+                                kv("MetricName", s("CPUUtilization")),
+                            ],
+                            v07_labels: vec![],
+                            start_time_unix_nano: 1_700_000_000_000_000_000,
+                            time_unix_nano: 1_700_000_060_000_000_000,
+                            count: 3,
+                            sum: 12.0,
+                            quantile_values: vec![],
+                        }],
+                    }),
+                }],
+            }],
+        }],
+    };
+    let mut description = String::new();
+    while request(&description).encoded_len() < 123 {
+        description.push('x');
+    }
+    let record = request(&description).encode_length_delimited_to_vec();
+    assert_eq!(record[0], b'{');
+
+    let mut metrics: Vec<CloudWatchMetric> = vec![];
+    decode_record(&record, MetricStreamFormat::Auto, &mut metrics).await.unwrap();
+    assert_eq!(metrics.len(), 1);
+    // named from the attributes, which only the 1.0 decoder reads
+    assert_eq!(metrics[0].namespace, "AWS/EC2");
+    assert_eq!(metrics[0].metric_name, "CPUUtilization");
+    // json that's really broken is still reported as json
+    let mut metrics: Vec<CloudWatchMetric> = vec![];
+    let err = decode_record(b"{\"metric_name\": ", MetricStreamFormat::Auto, &mut metrics).await;
+    assert!(matches!(err, Err(FirehoseError::InvalidJson(_))));
+}
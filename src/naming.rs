@@ -0,0 +1,281 @@
+use crate::config::{NameCase, NamingConfig};
+use crate::prometheus::sanitize_metric_name;
+use crate::structs::CloudWatchMetric;
+use convert_case::{Case, Casing};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Prefix,
+    Namespace,
+    Metric,
+    Unit,
+    Stat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field, Option<NameCase>),
+}
+
+#[derive(Debug, Clone)]
+struct Template {
+    parts: Vec<Part>,
+    case: NameCase,
+    alias: Option<String>,
+}
+
+impl Template {
+    fn parse(template: &str, case: NameCase, alias: Option<String>) -> anyhow::Result<Self> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while !rest.is_empty() {
+            let Some(open) = rest.find('{') else {
+                parts.push(Part::Literal(rest.to_string()));
+                break;
+            };
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| anyhow!("{template:?} has a {{ that isn't closed"))?
+                + open;
+            let (name, field_case) = match rest[open + 1..close].split_once(':') {
+                Some((name, case)) => (name, Some(parse_case(case)?)),
+                None => (&rest[open + 1..close], None),
+            };
+            let field = match name {
+                "prefix" => Field::Prefix,
+                "namespace" => Field::Namespace,
+                "metric" => Field::Metric,
+                "unit" => Field::Unit,
+                "stat" => Field::Stat,
+                _ => bail!("{template:?} has an unknown placeholder {{{name}}}"),
+            };
+            parts.push(Part::Field(field, field_case));
+            rest = &rest[close + 1..];
+        }
+        for part in parts.iter() {
+            if let Part::Literal(literal) = part {
+                if !literal.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+                    bail!("{template:?} has characters that can't be in a metric name");
+                }
+            }
+        }
+        // without these, every statistic of every metric would be written to the same series
+        for field in [Field::Metric, Field::Stat] {
+            if !parts.iter().any(|p| matches!(p, Part::Field(f, _) if *f == field)) {
+                bail!("{template:?} needs a {{{}}} placeholder", format!("{field:?}").to_lowercase());
+            }
+        }
+        Ok(Template { parts, case, alias })
+    }
+
+    fn render(&self, prefix: &str, metric: &CloudWatchMetric, stat: Option<&str>) -> String {
+        let mut name = String::new();
+        let mut skip_separator = false;
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(literal) => {
+                    let literal = match skip_separator {
+                        true => literal.strip_prefix(['_', ':']).unwrap_or(literal),
+                        false => literal,
+                    };
+                    name.push_str(literal);
+                    skip_separator = false;
+                }
+                Part::Field(field, case) => {
+                    let value = match field {
+                        // used as written, unless the template says otherwise
+                        Field::Prefix => match case {
+                            Some(case) => convert(prefix, *case),
+                            None => prefix.to_string(),
+                        },
+                        Field::Namespace => match self.alias.as_ref() {
+                            Some(alias) => alias.clone(),
+                            None => namespace_fragment(&metric.namespace, case.unwrap_or(self.case)),
+                        },
+                        Field::Metric => convert(&metric.metric_name, case.unwrap_or(self.case)),
+                        Field::Unit => convert(&metric.unit.to_string(), case.unwrap_or(self.case)),
+                        Field::Stat => convert(stat.unwrap_or_default(), case.unwrap_or(self.case)),
+                    };
+                    skip_separator = value.is_empty();
+                    name.push_str(&value);
+                }
+            }
+        }
+        if skip_separator && name.ends_with(['_', ':']) {
+            name.pop();
+        }
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            name.insert(0, '_');
+        }
+        name
+    }
+}
+
+/// `AWS/` is dropped and anything that can't be in a name separates words, e.g.
+/// `AWS/ECS/ContainerInsights` is `ecs_containerinsights`, or `ecs_container_insights` in `snake`.
+fn namespace_fragment(namespace: &str, case: NameCase) -> String {
+    let namespace = namespace.strip_prefix("AWS/").unwrap_or(namespace);
+    let words: Vec<&str> = namespace
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    match case {
+        // these find word boundaries of their own, and decide how to join them
+        NameCase::Snake | NameCase::Camel | NameCase::Pascal => convert(&words.join(" "), case),
+        _ => words
+            .iter()
+            .map(|word| convert(word, case))
+            .collect::<Vec<String>>()
+            .join("_"),
+    }
+}
+
+fn parse_case(case: &str) -> anyhow::Result<NameCase> {
+    Ok(match case {
+        "lower" => NameCase::Lower,
+        "upper" => NameCase::Upper,
+        "snake" => NameCase::Snake,
+        "camel" => NameCase::Camel,
+        "pascal" => NameCase::Pascal,
+        "keep" => NameCase::Keep,
+        _ => bail!("{case:?} isn't a case, use lower, upper, snake, camel, pascal or keep"),
+    })
+}
+
+fn convert(value: &str, case: NameCase) -> String {
+    let converted = match case {
+        NameCase::Lower => value.to_lowercase(),
+        NameCase::Upper => value.to_uppercase(),
+        NameCase::Snake => value.to_case(Case::Snake),
+        NameCase::Camel => value.to_case(Case::Camel),
+        NameCase::Pascal => value.to_case(Case::Pascal),
+        NameCase::Keep => value.to_string(),
+    };
+    sanitize_metric_name(converted)
+}
+
+/// Names forwarded metrics from a `naming` config, with its templates parsed once up front.
+#[derive(Debug, Clone)]
+pub struct MetricNamer {
+    prefix: String,
+    default: Template,
+    namespaces: HashMap<String, Template>,
+}
+
+impl Default for MetricNamer {
+    fn default() -> Self {
+        MetricNamer::new(&NamingConfig::default()).expect("the default naming template is valid")
+    }
+}
+
+impl MetricNamer {
+    pub fn new(config: &NamingConfig) -> anyhow::Result<Self> {
+        let default = Template::parse(&config.template, config.case, None)
+            .map_err(|e| anyhow!("naming.template: {e}"))?;
+        let mut namespaces = HashMap::new();
+        for (namespace, naming) in config.namespaces.iter() {
+            if let Some(alias) = naming.alias.as_ref() {
+                if !alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+                    bail!("naming.namespaces.{namespace}.alias: {alias:?} can't be in a metric name");
+                }
+            }
+            let template = naming.template.as_ref().unwrap_or(&config.template);
+            let parsed = Template::parse(template, naming.case.unwrap_or(config.case), naming.alias.clone())
+                .map_err(|e| anyhow!("naming.namespaces.{namespace}.template: {e}"))?;
+            namespaces.insert(namespace.clone(), parsed);
+        }
+        Ok(MetricNamer {
+            prefix: config.prefix.clone(),
+            default,
+            namespaces,
+        })
+    }
+
+    /// The name for one statistic of `metric`.  Percentiles have no `stat`, they're told apart
+    /// by a `quantile` label instead.
+    pub fn name(&self, metric: &CloudWatchMetric, stat: Option<&str>) -> String {
+        self.namespaces
+            .get(&metric.namespace)
+            .unwrap_or(&self.default)
+            .render(&self.prefix, metric, stat)
+    }
+}
+
+#[test]
+fn test_metric_names() {
+    use crate::config::NamespaceNaming;
+    use crate::structs::MetricUnit;
+    let metric = |namespace: &str| CloudWatchMetric {
+        namespace: namespace.to_string(),
+        metric_name: "CPUUtilization".to_string(),
+        unit: MetricUnit::BytesPerSecond,
+        ..Default::default()
+    };
+    let namer = MetricNamer::default();
+    assert_eq!(
+        namer.name(&metric("AWS/EC2"), Some("max")),
+        "firehose_ec2_cpuutilization_bytes_per_second_max"
+    );
+    assert_eq!(namer.name(&metric("AWS/EC2"), None), "firehose_ec2_cpuutilization_bytes_per_second");
+    for (namespace, fragment) in [
+        ("CWAgent", "cwagent"),
+        ("MyApp", "myapp"),
+        ("AWS/ECS/ContainerInsights", "ecs_containerinsights"),
+        ("Acme/Orders.Service", "acme_orders_service"),
+    ] {
+        assert_eq!(
+            namer.name(&metric(namespace), Some("sum")),
+            format!("firehose_{fragment}_cpuutilization_bytes_per_second_sum")
+        );
+    }
+    assert_eq!(namespace_fragment("AWS/ECS/ContainerInsights", NameCase::Snake), "ecs_container_insights");
+    assert_eq!(namespace_fragment("CWAgent", NameCase::Snake), "cw_agent");
+
+    let namer = MetricNamer::new(&NamingConfig {
+        prefix: String::new(),
+        template: "{prefix}_aws:{namespace}:{metric:snake}_{stat}".to_string(),
+        namespaces: [
+            (
+                "AWS/RDS".to_string(),
+                NamespaceNaming {
+                    template: Some("{namespace:upper}_{metric}_{unit}_{stat}".to_string()),
+                    case: Some(NameCase::Camel),
+                    ..Default::default()
+                },
+            ),
+            (
+                "CWAgent".to_string(),
+                NamespaceNaming {
+                    alias: Some("node".to_string()),
+                    ..Default::default()
+                },
+            ),
+        ]
+        .into(),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(namer.name(&metric("AWS/EC2"), Some("p99")), "aws:ec2:cpu_utilization_p99");
+    assert_eq!(namer.name(&metric("AWS/EC2"), None), "aws:ec2:cpu_utilization");
+    assert_eq!(
+        namer.name(&metric("AWS/RDS"), Some("max")),
+        "RDS_cpuUtilization_bytesPerSecond_max"
+    );
+    assert_eq!(namer.name(&metric("CWAgent"), Some("max")), "aws:node:cpu_utilization_max");
+
+    for template in ["{metric}_{statistic}", "{metric}", "{metric:title}_{stat}", "{metric}-{stat}", "{metric"] {
+        assert!(
+            MetricNamer::new(&NamingConfig {
+                template: template.to_string(),
+                ..Default::default()
+            })
+            .is_err(),
+            "{template}"
+        );
+    }
+}
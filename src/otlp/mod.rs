@@ -0,0 +1,57 @@
+pub mod v07;
+pub mod v1;
+
+use crate::structs::{CloudWatchMetric, MetricUnit};
+
+/// Decodes an otlp record without knowing which version the stream was set up with.  The two
+/// versions are close enough on the wire that a 0.7 record decodes cleanly as 1.0; it's told
+/// apart by its data points carrying labels in the field 1.0 reserves.
+pub fn decode_records_any_version(buf: &[u8]) -> anyhow::Result<Vec<CloudWatchMetric>> {
+    let requests = v1::decode_requests(buf)?;
+    if requests.iter().any(|r| r.has_v07_labels()) {
+        return v07::decode_records(buf);
+    }
+    Ok(requests.into_iter().flat_map(|r| r.into_cloudmetrics()).collect())
+}
+
+/// Splits an otlp metric name of the form `amazonaws.com/AWS/EC2/CPUUtilization` into the
+/// namespace and metric name, for streams that don't send them as attributes.
+pub fn names_from_otlp_metric(name: &str) -> (String, String) {
+    let name = name.strip_prefix("amazonaws.com/").unwrap_or(name);
+    match name.rsplit_once('/') {
+        Some((namespace, metric)) => (namespace.to_string(), metric.to_string()),
+        None => (String::new(), name.to_string()),
+    }
+}
+
+/// Names a summary quantile the way the json output format names the percentile statistic,
+/// e.g. 0.999 becomes `p99.9`.
+pub fn percentile_name(quantile: f64) -> String {
+    format!("p{}", (quantile * 1_000_000.0).round() / 10_000.0)
+}
+
+/// The stream name is only carried as the tail of the exporter arn, e.g.
+/// `arn:aws:cloudwatch:us-east-1:123456789012:metric-stream/my-stream`.
+pub fn metric_stream_name_from_arn(arn: &str) -> String {
+    match arn.rsplit_once("metric-stream/") {
+        Some((_, name)) => name.to_string(),
+        None => arn.to_string(),
+    }
+}
+
+/// CloudWatch passes its own unit names through to the otlp `unit` field, but be lenient and
+/// accept the UCUM spellings too.
+pub fn unit_from_otlp(unit: &str) -> MetricUnit {
+    match unit {
+        "Bytes" | "By" => MetricUnit::Bytes,
+        "Count" | "1" | "{Count}" => MetricUnit::Count,
+        "Percent" | "%" => MetricUnit::Percent,
+        "Seconds" | "s" => MetricUnit::Seconds,
+        "Milliseconds" | "ms" => MetricUnit::Milliseconds,
+        "Microseconds" | "us" => MetricUnit::Microseconds,
+        "Count/Second" | "1/s" | "{Count}/s" => MetricUnit::CountPerSecond,
+        "Bytes/Second" | "By/s" => MetricUnit::BytesPerSecond,
+        "None" | "" => MetricUnit::None,
+        _ => MetricUnit::Unknown,
+    }
+}
@@ -0,0 +1,220 @@
+//! The subset of the OpenTelemetry 0.7.0 metrics protobufs that CloudWatch metric streams emit.
+//! 0.7 predates the stable data model: summaries are `DoubleSummary` and data points carry plain
+//! string labels rather than typed attributes.  Resource attributes are unchanged, so those
+//! messages are shared with the 1.0 decoder.
+use crate::otlp::v1::{string_attribute, Resource, ValueAtQuantile};
+use crate::otlp::{
+    metric_stream_name_from_arn, names_from_otlp_metric, percentile_name, unit_from_otlp,
+};
+use crate::structs::{CloudWatchMetric, DimensionMap, MetricValue};
+use prost::bytes::Buf;
+use prost::Message;
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub instrumentation_library_metrics: Vec<InstrumentationLibraryMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InstrumentationLibraryMetrics {
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+    #[prost(message, optional, tag = "11")]
+    pub double_summary: Option<DoubleSummary>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DoubleSummary {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<DoubleSummaryDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DoubleSummaryDataPoint {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<StringKeyValue>,
+    #[prost(fixed64, tag = "2")]
+    pub start_time_unix_nano: u64,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    pub count: u64,
+    #[prost(double, tag = "5")]
+    pub sum: f64,
+    #[prost(message, repeated, tag = "6")]
+    pub quantile_values: Vec<ValueAtQuantile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StringKeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// Decodes a firehose record holding one or more size-delimited 0.7 `ExportMetricsServiceRequest`s.
+pub fn decode_records(mut buf: &[u8]) -> anyhow::Result<Vec<CloudWatchMetric>> {
+    let mut metrics: Vec<CloudWatchMetric> = vec![];
+    while buf.has_remaining() {
+        let request = ExportMetricsServiceRequest::decode_length_delimited(&mut buf)?;
+        metrics.extend(request.into_cloudmetrics());
+    }
+    Ok(metrics)
+}
+
+impl ExportMetricsServiceRequest {
+    pub fn into_cloudmetrics(self) -> Vec<CloudWatchMetric> {
+        let mut metrics: Vec<CloudWatchMetric> = vec![];
+        for resource_metrics in self.resource_metrics {
+            let resource_attributes = resource_metrics
+                .resource
+                .map(|r| r.attributes)
+                .unwrap_or_default();
+            let account_id = string_attribute(&resource_attributes, "cloud.account.id");
+            let region = string_attribute(&resource_attributes, "cloud.region");
+            let resource_namespace = string_attribute(&resource_attributes, "Namespace");
+            let metric_stream_name = metric_stream_name_from_arn(&string_attribute(
+                &resource_attributes,
+                "aws.exporter.arn",
+            ));
+            for metric in resource_metrics
+                .instrumentation_library_metrics
+                .into_iter()
+                .flat_map(|i| i.metrics)
+            {
+                let unit = unit_from_otlp(&metric.unit);
+                let Some(summary) = metric.double_summary else {
+                    debug!("Skipping non-summary otlp metric {}", metric.name);
+                    continue;
+                };
+                let (name_namespace, name_metric) = names_from_otlp_metric(&metric.name);
+                for point in summary.data_points {
+                    let mut namespace = resource_namespace.clone();
+                    let mut metric_name = String::new();
+                    let mut dimensions: HashMap<String, String> = HashMap::new();
+                    for label in point.labels {
+                        match label.key.as_str() {
+                            "Namespace" => namespace = label.value,
+                            "MetricName" => metric_name = label.value,
+                            _ => {
+                                dimensions.insert(label.key, label.value);
+                            }
+                        }
+                    }
+                    if namespace.is_empty() {
+                        namespace = name_namespace.clone();
+                    }
+                    if metric_name.is_empty() {
+                        metric_name = name_metric.clone();
+                    }
+                    let mut value = MetricValue {
+                        sum: Some(point.sum),
+                        count: Some(point.count as f64),
+                        ..Default::default()
+                    };
+                    for q in point.quantile_values.iter() {
+                        if q.quantile == 0.0 {
+                            value.min = Some(q.value);
+                        } else if q.quantile == 1.0 {
+                            value.max = Some(q.value);
+                        } else {
+                            value
+                                .additional
+                                .insert(percentile_name(q.quantile), q.value);
+                        }
+                    }
+                    metrics.push(CloudWatchMetric {
+                        metric_stream_name: metric_stream_name.clone(),
+                        account_id: account_id.clone(),
+                        region: region.clone(),
+                        namespace,
+                        metric_name,
+                        dimensions: DimensionMap(dimensions),
+                        timestamp: (point.time_unix_nano / 1_000_000) as i64,
+                        value,
+                        unit: unit.clone(),
+                    });
+                }
+            }
+        }
+        metrics
+    }
+}
+
+#[test]
+fn test_decode_records() {
+    use crate::otlp::v1::{any_value, AnyValue, KeyValue};
+
+    let label = |k: &str, v: &str| StringKeyValue {
+        key: k.to_string(),
+        value: v.to_string(),
+    };
+    let request = ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(Resource {
+                attributes: vec![KeyValue {
+                    key: "cloud.account.id".to_string(),
+                    value: Some(AnyValue {
+                        value: Some(any_value::Value::StringValue("123456789012".to_string())),
+                    }),
+                }],
+            }),
+            instrumentation_library_metrics: vec![InstrumentationLibraryMetrics {
+                metrics: vec![Metric {
+                    name: "amazonaws.com/AWS/ELB/Latency".to_string(),
+                    description: String::new(),
+                    unit: "Seconds".to_string(),
+                    double_summary: Some(DoubleSummary {
+                        data_points: vec![DoubleSummaryDataPoint {
+                            labels: vec![label("LoadBalancerName", "my-elb")],
+                            start_time_unix_nano: 0,
+                            time_unix_nano: 1_700_000_060_000_000_000,
+                            count: 2,
+                            sum: 0.5,
+                            quantile_values: vec![ValueAtQuantile {
+                                quantile: 1.0,
+                                value: 0.4,
+                            }],
+                        }],
+                    }),
+                }],
+            }],
+        }],
+    };
+    let buf = request.encode_length_delimited_to_vec();
+
+    // 0.7 labels sit in a field 1.0 reserves, which is how auto-detection tells them apart
+    assert!(crate::otlp::v1::decode_requests(&buf).unwrap()[0].has_v07_labels());
+    let detected = crate::otlp::decode_records_any_version(&buf).unwrap();
+    assert_eq!(detected[0].dimensions.0.get("LoadBalancerName").unwrap(), "my-elb");
+
+    let metrics = decode_records(&buf).unwrap();
+    assert_eq!(metrics.len(), 1);
+    let m = &metrics[0];
+    assert_eq!(m.account_id, "123456789012");
+    assert_eq!(m.namespace, "AWS/ELB");
+    assert_eq!(m.metric_name, "Latency");
+    assert_eq!(m.value.max, Some(0.4));
+    assert_eq!(m.dimensions.0.get("LoadBalancerName").unwrap(), "my-elb");
+}
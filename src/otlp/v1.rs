@@ -0,0 +1,315 @@
+//! The subset of the OpenTelemetry 1.0 metrics protobufs that CloudWatch metric streams emit.
+//! CloudWatch only ever sends summaries, so the other data point types are left out and prost
+//! skips over them.
+use crate::otlp::{metric_stream_name_from_arn, names_from_otlp_metric, percentile_name, unit_from_otlp};
+use crate::structs::{CloudWatchMetric, DimensionMap, MetricValue};
+use prost::bytes::Buf;
+use prost::Message;
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ScopeMetrics {
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+    #[prost(message, optional, tag = "11")]
+    pub summary: Option<Summary>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Summary {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<SummaryDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SummaryDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    /// Reserved in 1.0; 0.7 kept its labels here.  Only read to tell the versions apart.
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub v07_labels: Vec<Vec<u8>>,
+    #[prost(fixed64, tag = "2")]
+    pub start_time_unix_nano: u64,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    pub count: u64,
+    #[prost(double, tag = "5")]
+    pub sum: f64,
+    #[prost(message, repeated, tag = "6")]
+    pub quantile_values: Vec<ValueAtQuantile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueAtQuantile {
+    #[prost(double, tag = "1")]
+    pub quantile: f64,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AnyValue {
+    #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4, 6")]
+    pub value: Option<any_value::Value>,
+}
+
+pub mod any_value {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(string, tag = "1")]
+        StringValue(String),
+        #[prost(bool, tag = "2")]
+        BoolValue(bool),
+        #[prost(int64, tag = "3")]
+        IntValue(i64),
+        #[prost(double, tag = "4")]
+        DoubleValue(f64),
+        #[prost(message, tag = "6")]
+        KvlistValue(super::KeyValueList),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<KeyValue>,
+}
+
+impl AnyValue {
+    fn as_string(&self) -> String {
+        match &self.value {
+            Some(any_value::Value::StringValue(s)) => s.clone(),
+            Some(any_value::Value::BoolValue(b)) => b.to_string(),
+            Some(any_value::Value::IntValue(i)) => i.to_string(),
+            Some(any_value::Value::DoubleValue(d)) => d.to_string(),
+            Some(any_value::Value::KvlistValue(_)) | None => String::new(),
+        }
+    }
+}
+
+fn find_attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a AnyValue> {
+    attributes
+        .iter()
+        .find(|kv| kv.key == key)
+        .and_then(|kv| kv.value.as_ref())
+}
+
+pub(crate) fn string_attribute(attributes: &[KeyValue], key: &str) -> String {
+    find_attribute(attributes, key)
+        .map(|v| v.as_string())
+        .unwrap_or_default()
+}
+
+fn non_empty_or(value: String, fallback: &str) -> String {
+    if value.is_empty() {
+        fallback.to_string()
+    } else {
+        value
+    }
+}
+
+/// Decodes a firehose record holding one or more size-delimited `ExportMetricsServiceRequest`s.
+pub fn decode_records(buf: &[u8]) -> anyhow::Result<Vec<CloudWatchMetric>> {
+    Ok(decode_requests(buf)?
+        .into_iter()
+        .flat_map(|r| r.into_cloudmetrics())
+        .collect())
+}
+
+pub fn decode_requests(mut buf: &[u8]) -> anyhow::Result<Vec<ExportMetricsServiceRequest>> {
+    let mut requests: Vec<ExportMetricsServiceRequest> = vec![];
+    while buf.has_remaining() {
+        requests.push(ExportMetricsServiceRequest::decode_length_delimited(&mut buf)?);
+    }
+    Ok(requests)
+}
+
+impl ExportMetricsServiceRequest {
+    /// Whether any data point has 0.7 labels, i.e. this was really written as 0.7.
+    pub fn has_v07_labels(&self) -> bool {
+        self.resource_metrics
+            .iter()
+            .flat_map(|r| r.scope_metrics.iter())
+            .flat_map(|s| s.metrics.iter())
+            .filter_map(|m| m.summary.as_ref())
+            .flat_map(|s| s.data_points.iter())
+            .any(|p| !p.v07_labels.is_empty())
+    }
+
+    pub fn into_cloudmetrics(self) -> Vec<CloudWatchMetric> {
+        let mut metrics: Vec<CloudWatchMetric> = vec![];
+        for resource_metrics in self.resource_metrics {
+            let resource_attributes = resource_metrics
+                .resource
+                .map(|r| r.attributes)
+                .unwrap_or_default();
+            let account_id = string_attribute(&resource_attributes, "cloud.account.id");
+            let region = string_attribute(&resource_attributes, "cloud.region");
+            let metric_stream_name = metric_stream_name_from_arn(&string_attribute(
+                &resource_attributes,
+                "aws.exporter.arn",
+            ));
+            for metric in resource_metrics
+                .scope_metrics
+                .into_iter()
+                .flat_map(|s| s.metrics)
+            {
+                let unit = unit_from_otlp(&metric.unit);
+                let Some(summary) = metric.summary else {
+                    debug!("Skipping non-summary otlp metric {}", metric.name);
+                    continue;
+                };
+                let (name_namespace, name_metric) = names_from_otlp_metric(&metric.name);
+                for point in summary.data_points {
+                    let mut dimensions: HashMap<String, String> = HashMap::new();
+                    if let Some(AnyValue {
+                        value: Some(any_value::Value::KvlistValue(list)),
+                    }) = find_attribute(&point.attributes, "Dimensions")
+                    {
+                        for kv in list.values.iter() {
+                            let value = kv.value.as_ref().map(|v| v.as_string()).unwrap_or_default();
+                            dimensions.insert(kv.key.clone(), value);
+                        }
+                    }
+                    let mut value = MetricValue {
+                        sum: Some(point.sum),
+                        count: Some(point.count as f64),
+                        ..Default::default()
+                    };
+                    for q in point.quantile_values.iter() {
+                        if q.quantile == 0.0 {
+                            value.min = Some(q.value);
+                        } else if q.quantile == 1.0 {
+                            value.max = Some(q.value);
+                        } else {
+                            value
+                                .additional
+                                .insert(percentile_name(q.quantile), q.value);
+                        }
+                    }
+                    metrics.push(CloudWatchMetric {
+                        metric_stream_name: metric_stream_name.clone(),
+                        account_id: account_id.clone(),
+                        region: region.clone(),
+                        namespace: non_empty_or(string_attribute(&point.attributes, "Namespace"), &name_namespace),
+                        metric_name: non_empty_or(string_attribute(&point.attributes, "MetricName"), &name_metric),
+                        dimensions: DimensionMap(dimensions),
+                        timestamp: (point.time_unix_nano / 1_000_000) as i64,
+                        value,
+                        unit: unit.clone(),
+                    });
+                }
+            }
+        }
+        metrics
+    }
+}
+
+#[test]
+fn test_decode_records() {
+    fn kv(key: &str, value: any_value::Value) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue { value: Some(value) }),
+        }
+    }
+    let s = |v: &str| any_value::Value::StringValue(v.to_string());
+    let request = ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(Resource {
+                attributes: vec![
+                    kv("cloud.account.id", s("123456789012")),
+                    kv("cloud.region", s("us-east-1")),
+                    kv(
+                        "aws.exporter.arn",
+                        s("arn:aws:cloudwatch:us-east-1:123456789012:metric-stream/my-stream"),
+                    ),
+                ],
+            }),
+            scope_metrics: vec![ScopeMetrics {
+                metrics: vec![Metric {
+                    name: "amazonaws.com/AWS/EC2/CPUUtilization".to_string(),
+                    description: String::new(),
+                    unit: "Percent".to_string(),
+                    summary: Some(Summary {
+                        data_points: vec![SummaryDataPoint {
+                            attributes: vec![
+                                kv("Namespace", s("AWS/EC2")),
+                                kv("MetricName", s("CPUUtilization")),
+                                kv(
+                                    "Dimensions",
+                                    any_value::Value::KvlistValue(KeyValueList {
+                                        values: vec![kv("InstanceId", s("i-0123"))],
+                                    }),
+                                ),
+                            ],
+                            v07_labels: vec![],
+                            start_time_unix_nano: 1_700_000_000_000_000_000,
+                            time_unix_nano: 1_700_000_060_000_000_000,
+                            count: 3,
+                            sum: 12.0,
+                            quantile_values: vec![
+                                ValueAtQuantile { quantile: 0.0, value: 1.0 },
+                                ValueAtQuantile { quantile: 1.0, value: 9.0 },
+                            ],
+                        }],
+                    }),
+                }],
+            }],
+        }],
+    };
+    let mut buf = request.encode_length_delimited_to_vec();
+    buf.extend(request.encode_length_delimited_to_vec());
+
+    let metrics = decode_records(&buf).unwrap();
+    assert_eq!(metrics.len(), 2);
+    let m = &metrics[0];
+    assert_eq!(m.metric_stream_name, "my-stream");
+    assert_eq!(m.account_id, "123456789012");
+    assert_eq!(m.namespace, "AWS/EC2");
+    assert_eq!(m.metric_name, "CPUUtilization");
+    assert_eq!(m.timestamp, 1_700_000_060_000);
+    assert_eq!(m.value.min, Some(1.0));
+    assert_eq!(m.value.max, Some(9.0));
+    assert_eq!(m.value.count, Some(3.0));
+    assert_eq!(m.dimensions.0.get("InstanceId").unwrap(), "i-0123");
+}
@@ -1,19 +1,26 @@
 use crate::consts::PROM_NAMESPACE;
-use crate::structs::{CloudWatchMetric, MetricUnit};
+use crate::batch::metric_name;
+use crate::naming::MetricNamer;
+use crate::flusher::FlusherSettings;
+use crate::remote_write::{
+    RemoteWriteProtocol, HEADER_EXEMPLARS_WRITTEN, HEADER_HISTOGRAMS_WRITTEN, HEADER_SAMPLES_WRITTEN,
+    HEADER_VERSION, METRIC_NAME_LABEL,
+};
+use crate::structs::{CloudWatchMetric, MetricUnit, Statistic};
+use crate::tenant::Tenant;
+use axum::body::Bytes;
+use axum::http::header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER};
 use axum::http::StatusCode;
 use lazy_static::lazy_static;
-use prometheus::core::{Collector, Metric};
-use prometheus::{labels, opts, register_counter_vec, register_gauge_vec, register_histogram_vec, CounterVec, Gauge, GaugeVec, HistogramVec, TextEncoder, Error};
-use prometheus_remote_write::WriteRequest;
-use reqwest::Client;
+use prometheus::{histogram_opts, opts, register_counter_vec_with_registry, register_gauge_vec_with_registry, register_histogram_vec_with_registry, CounterVec, GaugeVec, HistogramVec, Registry, TextEncoder};
+use prometheus_remote_write::{Label, Sample, TimeSeries};
 use std::collections::HashMap;
-use std::env;
 use std::sync::Arc;
-use convert_case::{Case, Casing};
+use std::time::Duration;
 use tokio::sync::Mutex;
-use url::Url;
 use std::collections::BTreeMap;
-use crate::aws::{AWSState, get_dimensions};
+
+type DimensionHash = Arc<Mutex<HashMap<String, Vec<String>>>>;
 
 macro_rules! app_opts {
     ($a:expr, $b:expr) => {
@@ -26,219 +33,383 @@ macro_rules! app_histogram_opts {
     };
 }
 
-type GaugeHash = Arc<Mutex<HashMap<String, GaugeVec>>>;
-type CounterHash = Arc<Mutex<HashMap<String, CounterVec>>>;
-type HistoHash = Arc<Mutex<HashMap<String, HistogramVec>>>;
-
-type DimensionHash = Arc<Mutex<HashMap<String, Vec<String>>>>;
-
 lazy_static! {
-    pub static ref GAUGES: GaugeHash = Arc::new(Mutex::new(HashMap::new()));
-    pub static ref COUNTERS: CounterHash = Arc::new(Mutex::new(HashMap::new()));
-    pub static ref HISTOGRAMS: HistoHash = Arc::new(Mutex::new(HashMap::new()));
     pub static ref DIMENSION_HASH: DimensionHash = Arc::new(Mutex::new(HashMap::new()));
-    pub static ref APP_INFO: GaugeVec = register_gauge_vec!(
+    // our own metrics live apart from the default registry, so nothing else can end up on /metrics
+    pub static ref SELF_REGISTRY: Registry = Registry::new();
+    pub static ref APP_INFO: GaugeVec = register_gauge_vec_with_registry!(
         app_opts!(
             "firehose_app_info",
             "static app labels that potentially only change at restart"
         ),
-        &["crate_version", "git_hash"]
+        &["crate_version", "git_hash"],
+        SELF_REGISTRY
     )
     .unwrap();
-    pub static ref STREAMS_RECEIVED: CounterVec = register_counter_vec!(
+    pub static ref STREAMS_RECEIVED: CounterVec = register_counter_vec_with_registry!(
         app_opts!(
             "self_kinesis_payloads_received_count",
             "The number of kinesis payloads received"
         ),
-        &[]
+        &[],
+        SELF_REGISTRY
     )
     .unwrap();
-    pub static ref TOTAL_WRITES_SENT: CounterVec = register_counter_vec!(
+    pub static ref TOTAL_WRITES_SENT: CounterVec = register_counter_vec_with_registry!(
         app_opts!(
             "self_remote_writes_sent_count",
             "The number of remnote writes attempted"
         ),
-        &["status_code"]
+        &["destination", "status_code"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref REMOTE_WRITE_WRITTEN: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_remote_write_written_count",
+            "What the receiver reported writing, from the remote write 2.0 response headers"
+        ),
+        &["destination", "kind"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref REMOTE_WRITE_RETRIES: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_remote_write_retries_count",
+            "The number of remote writes that failed and were retried"
+        ),
+        &["destination"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref REMOTE_WRITE_GIVE_UPS: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_remote_write_give_ups_count",
+            "The number of batches dropped after remote write stopped retrying"
+        ),
+        &["destination", "reason"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref WAL_SEGMENTS: GaugeVec = register_gauge_vec_with_registry!(
+        app_opts!(
+            "self_wal_segments",
+            "The number of batches in the write-ahead log waiting to be sent"
+        ),
+        &["destination"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref WAL_BYTES: GaugeVec = register_gauge_vec_with_registry!(
+        app_opts!(
+            "self_wal_bytes",
+            "The on-disk size of the write-ahead log"
+        ),
+        &["destination"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref WAL_DROPPED_SEGMENTS: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_wal_dropped_segments_count",
+            "The number of unsent batches removed from the write-ahead log by retention"
+        ),
+        &["destination", "reason"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref DESTINATION_DROPPED_BATCHES: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_destination_dropped_batches_count",
+            "The number of batches a destination missed because its queue was full"
+        ),
+        &["destination"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref RECORDS_FAILED: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_records_failed_count",
+            "The number of firehose records that could not be decoded"
+        ),
+        &["reason"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref FLUSH_QUEUE_DEPTH: GaugeVec = register_gauge_vec_with_registry!(
+        app_opts!(
+            "self_flush_queue_depth",
+            "The number of ingested batches waiting for the flusher"
+        ),
+        &["destination"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref FLUSH_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        app_histogram_opts!(
+            "self_flush_duration_seconds",
+            "How long each flush to remote write took",
+            vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+        ),
+        &["destination"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref FLUSH_BATCH_SIZE: HistogramVec = register_histogram_vec_with_registry!(
+        app_histogram_opts!(
+            "self_flush_batch_size",
+            "The number of samples sent in each flush",
+            vec![10.0, 100.0, 1000.0, 5000.0, 10000.0, 50000.0, 100000.0]
+        ),
+        &["destination"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref CONFIG_RELOADS: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_config_reloads_count",
+            "The number of config reloads attempted, by whether the new config was applied"
+        ),
+        &["result"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref CONFIG_GENERATION: GaugeVec = register_gauge_vec_with_registry!(
+        app_opts!(
+            "self_config_generation",
+            "How many times a config has been applied since startup"
+        ),
+        &[],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref ACCESS_KEY_FAILURES: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_access_key_failures_count",
+            "The number of deliveries rejected for a missing or invalid access key"
+        ),
+        &["reason"],
+        SELF_REGISTRY
+    )
+    .unwrap();
+    pub static ref TLS_RELOADS: CounterVec = register_counter_vec_with_registry!(
+        app_opts!(
+            "self_tls_reloads_count",
+            "The number of times a changed tls certificate was loaded, by whether it could be"
+        ),
+        &["result"],
+        SELF_REGISTRY
     )
     .unwrap();
 }
 
-pub async fn push_firehose_metrics() -> anyhow::Result<bool> {
-    let addr = env::var("PROM_WRITE_ADDR").expect("Can't push without PROM_WRITE_ADDR defined");
-    let user: Option<String> = env::var("PROM_USERNAME").ok();
-    let pass: Option<String> = env::var("PROM_PASSWORD").ok();
+/// Our own metrics in the text exposition format, for `/metrics`.
+pub fn gather_self_metrics() -> anyhow::Result<String> {
+    Ok(TextEncoder::new().encode_to_string(&SELF_REGISTRY.gather())?)
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    /// Worth sending again later: connection failures, 5xx and 429.
+    Retryable {
+        reason: String,
+        retry_after: Option<Duration>,
+    },
+    /// The receiver refused the data, sending it again won't change its mind.
+    Permanent(String),
+}
 
-    let client = Client::new();
+pub async fn send_write_request(
+    destination: &FlusherSettings,
+    body: Bytes,
+    protocol: RemoteWriteProtocol,
+    tenant: Option<&Tenant>,
+) -> Result<(), WriteError> {
+    let name = destination.name.as_str();
 
-    let metric_families = prometheus::gather();
-    let text_metric_families = TextEncoder::new().encode_to_string(&metric_families)?;
-    //info!("{text_metric_families}");
-    let encoded_write_request = WriteRequest::from_text_format(text_metric_families).unwrap();
-    //info!("{:#?}", encoded_write_request);
-    let url = format!("{addr}/api/v1/write");
-    let body = encoded_write_request.encode_compressed()?;
-    let rs = client.post(url).body(body).send().await?;
+    let client = &destination.client;
+    let request = client
+        .post(&destination.url)
+        .header(CONTENT_TYPE, protocol.content_type())
+        .header(CONTENT_ENCODING, "snappy")
+        .header(HEADER_VERSION, protocol.version())
+        .body(body);
+    // a credentials file that can't be read may just be mid-rotation, and aws credentials may
+    // just be expiring, so neither gives up on the batch
+    let retryable = |e: anyhow::Error| WriteError::Retryable {
+        reason: e.to_string(),
+        retry_after: None,
+    };
+    // the tenant comes with the batch rather than from the config, so that a batch held in the
+    // wal still goes where it was meant to after the tenants have been reconfigured
+    let request = match tenant {
+        Some(tenant) => request.header(&tenant.header, &tenant.id),
+        None => request,
+    };
+    let mut request = destination
+        .auth
+        .apply(request)
+        .and_then(|r| Ok(r.build()?))
+        .map_err(retryable)?;
+    if let Some(sigv4) = destination.sigv4.as_ref() {
+        sigv4.sign(&mut request).await.map_err(retryable)?;
+    }
+    let rs = match client.execute(request).await {
+        Ok(rs) => rs,
+        Err(e) => {
+            TOTAL_WRITES_SENT.with_label_values(&[name, "error"]).inc();
+            return Err(WriteError::Retryable {
+                reason: e.to_string(),
+                retry_after: None,
+            });
+        }
+    };
+    let status = rs.status();
     TOTAL_WRITES_SENT
-        .with_label_values(&[rs.status().clone().as_str()])
+        .with_label_values(&[name, status.as_str()])
         .inc();
-    if rs.status().clone() == StatusCode::BAD_REQUEST {
-        let text = rs.text().await?;
+    if status.is_success() {
+        for (header, kind) in [
+            (HEADER_SAMPLES_WRITTEN, "samples"),
+            (HEADER_HISTOGRAMS_WRITTEN, "histograms"),
+            (HEADER_EXEMPLARS_WRITTEN, "exemplars"),
+        ] {
+            if let Some(written) = rs
+                .headers()
+                .get(header)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+            {
+                REMOTE_WRITE_WRITTEN.with_label_values(&[name, kind]).inc_by(written as f64);
+            }
+        }
+        return Ok(());
+    }
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        // like prometheus, only the delay-seconds form of Retry-After is understood
+        let retry_after = rs
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let text = rs.text().await.unwrap_or_default();
+        return Err(WriteError::Retryable {
+            reason: format!("{status}: {text}"),
+            retry_after,
+        });
+    }
+    let text = rs.text().await.unwrap_or_default();
+    if status == StatusCode::BAD_REQUEST {
         match text.as_str().trim() {
             "out of order sample"
             | "duplicate sample for timestamp"
             | "Out of order sample from remote write" => {
-                debug!("One or more samples in this push were duplicated or out-of-order.  Not much we can do about this.")
-            }
-            _ => {
-                // 2024-11-01: if we don't clear the collectors, the daemon just keeps sending the bad data every
-                // attempt
-                clear_collectors().await;
-
-                bail!("400 Bad request: {text}")
+                debug!("One or more samples in this push were duplicated or out-of-order.  Not much we can do about this.");
+                return Ok(());
             }
+            _ => {}
         };
     }
-    // now that we've sent the metrics, lets delete them so that they don't pollute future samples
-    clear_collectors().await;
-    Ok(true)
+    Err(WriteError::Permanent(format!("{status}: {text}")))
 }
 
-pub async fn clear_collectors() {
-    let mut collectors = GAUGES.lock().await;
-    for (key, collector) in collectors.iter() {
-        if let Err(e) = prometheus::unregister(Box::new(collector.clone())) {
-            error!("Couldn't unregister collector: {e}");
-        }
+/// Builds the remote-write series for one cloudwatch metric: a gauge per statistic, named by
+/// `naming`.  Percentiles share the name without a statistic and are told apart by a `quantile`
+/// label, like a prometheus summary.
+pub fn to_timeseries(incoming_metric: &CloudWatchMetric, naming: &MetricNamer) -> Vec<TimeSeries> {
+    if let MetricUnit::Unknown = incoming_metric.unit {
+        warn!("Received unknown metric, {:#?}", incoming_metric);
+        return vec![];
+    }
+
+    let mut labels: BTreeMap<String, String> = BTreeMap::new();
+    labels.insert("metric_stream_name".to_string(), incoming_metric.metric_stream_name.clone());
+    labels.insert("account_id".to_string(), incoming_metric.account_id.clone());
+    labels.insert("region".to_string(), incoming_metric.region.clone());
+    for dim in incoming_metric.dimensions.to_labels_values() {
+        labels.insert(dim.key, dim.value);
     }
-    collectors.clear();
-    let mut collectors = COUNTERS.lock().await;
-    for (key, collector) in collectors.iter() {
-        if let Err(e) = prometheus::unregister(Box::new(collector.clone())) {
-            error!("Couldn't unregister collector: {e}");
+
+    let value = &incoming_metric.value;
+    let timestamp = incoming_metric.timestamp;
+    let mut series: Vec<TimeSeries> = vec![];
+    for (suffix, stat_value) in [
+        ("max", value.max),
+        ("min", value.min),
+        ("sum", value.sum),
+        ("count", value.count),
+    ] {
+        if let Some(v) = stat_value {
+            series.push(new_series(naming.name(incoming_metric, Some(suffix)), labels.clone(), timestamp, v));
         }
     }
-    collectors.clear();
-    let mut collectors = HISTOGRAMS.lock().await;
-    for (key, collector) in collectors.iter() {
-        if let Err(e) = prometheus::unregister(Box::new(collector.clone())) {
-            error!("Couldn't unregister collector: {e}");
+    for (stat_name, stat_value) in value.additional.iter() {
+        match Statistic::parse(stat_name) {
+            Statistic::Percentile(q) => {
+                let mut labels = labels.clone();
+                labels.insert("quantile".to_string(), q.to_string());
+                series.push(new_series(naming.name(incoming_metric, None), labels, timestamp, *stat_value));
+            }
+            Statistic::Other(suffix) => {
+                let name = naming.name(incoming_metric, Some(&suffix));
+                series.push(new_series(name, labels.clone(), timestamp, *stat_value));
+            }
         }
     }
-    collectors.clear();
+    series
 }
 
-pub async fn record_metric(incoming_metric: CloudWatchMetric) -> anyhow::Result<()> {
-    let namespace: String = incoming_metric
-        .clone()
-        .namespace
-        .split("/")
-        .collect::<Vec<&str>>()[1]
-        .to_lowercase();
-
-    let metric_name = format!(
-        "{namespace}_{}_{}",
-        sanitize_metric_name(incoming_metric.metric_name.to_lowercase()),
-        &incoming_metric.unit
-    );
-
-    let dims = incoming_metric.dimensions.to_labels_values();
-    let mut labels: Vec<&str> = vec!["metric_stream_name", "account_id", "region"];
-    let dim_strs = get_dimensions(incoming_metric.region.clone(),incoming_metric.namespace.clone(),incoming_metric.metric_name.clone()).await;
-    labels.extend(dim_strs.iter().map(|s| { s.as_str() }));
-    let mut lv_tree: BTreeMap<&str, &str> = BTreeMap::new();
-    for label in labels.iter() {
-        lv_tree.insert(label, "");
+/// Remote write wants labels sorted by name, and treats an empty value the same as no label.
+fn new_series(name: String, mut labels: BTreeMap<String, String>, timestamp: i64, value: f64) -> TimeSeries {
+    labels.insert(METRIC_NAME_LABEL.to_string(), name);
+    TimeSeries {
+        labels: labels
+            .into_iter()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(name, value)| Label { name, value })
+            .collect(),
+        samples: vec![Sample { value, timestamp }],
     }
-    lv_tree.insert("metric_stream_name", incoming_metric.metric_stream_name.as_str());
-    lv_tree.insert("account_id", incoming_metric.account_id.as_str());
-    lv_tree.insert("region", incoming_metric.region.as_str());
-
-    let ordered_labels: Vec<&str> =lv_tree.iter().map(|(k, v)| *k).collect();
-
-    match incoming_metric.unit {
-        MetricUnit::Count
-        | MetricUnit::Bytes
-        | MetricUnit::Percent
-        | MetricUnit::Average
-        | MetricUnit::Seconds
-        | MetricUnit::CountPerSecond
-        | MetricUnit::BytesPerSecond
-        | MetricUnit::Milliseconds
-        | MetricUnit::Microseconds
-        | MetricUnit::None => {
-
-            if incoming_metric.value.max.is_some() {
-                let mut local_lv_tree = lv_tree.clone();
-                for dim in dims.iter() {
-                    local_lv_tree.insert(dim.key.as_str(), dim.value.as_str());
-                }
-                let ordered_values: Vec<&str> = local_lv_tree.iter().map(|(k, v)| *v).collect();
-                let full_metric_name = format!("{metric_name}_max");
-                let outgoing_gauge = get_or_register_metric(full_metric_name,&ordered_labels).await;
-                let m = match outgoing_gauge.get_metric_with_label_values(&ordered_values) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        match e {
-                            Error::AlreadyReg => {}
-                            Error::InconsistentCardinality { .. } => {
-                                warn!("{metric_name} inconsistent cardinality\n labels: {ordered_labels:#?}\nvalues: {ordered_values:#?}");
-                            }
-                            Error::Msg(_) => {}
-                            Error::Io(_) => {}
-                            Error::Protobuf(_) => {}
-                        }
-                        return Err(anyhow!(e));
-                    }
-                };
-                m.set_timestamp_ms(incoming_metric.timestamp as i64);
-                m.set(incoming_metric.value.max.unwrap() as f64);
-            }
-            if incoming_metric.value.min.is_some() {
-                let mut local_lv_tree = lv_tree.clone();
-                for dim in dims.iter() {
-                    local_lv_tree.insert(dim.key.as_str(), dim.value.as_str());
-                }
-                let ordered_values: Vec<&str> = local_lv_tree.iter().map(|(k, v)| *v).collect();
-                let full_metric_name = format!("{metric_name}_min");
-                let outgoing_gauge = get_or_register_metric(full_metric_name, &ordered_labels).await;
+}
 
-                let m = outgoing_gauge.with_label_values(&ordered_values.clone());
-                m.set_timestamp_ms(incoming_metric.timestamp as i64);
-                m.set(incoming_metric.value.min.unwrap() as f64);
-            }
-            if incoming_metric.value.sum.is_some() {
-                let mut local_lv_tree = lv_tree.clone();
-                for dim in dims.iter() {
-                    local_lv_tree.insert(dim.key.as_str(), dim.value.as_str());
-                }
-                let ordered_values: Vec<&str> = local_lv_tree.iter().map(|(k, v)| *v).collect();
-                let full_metric_name = format!("{metric_name}_sum");
-                let outgoing_gauge = get_or_register_metric(full_metric_name, &ordered_labels).await;
-                let m = outgoing_gauge.with_label_values(&ordered_values.clone());
-                m.set_timestamp_ms(incoming_metric.timestamp as i64);
-                m.set(incoming_metric.value.sum.unwrap() as f64);
-            }
-            if incoming_metric.value.count.is_some() {
-                let mut local_lv_tree = lv_tree.clone();
-                for dim in dims.iter() {
-                    local_lv_tree.insert(dim.key.as_str(), dim.value.as_str());
-                }
-                let ordered_values: Vec<&str> = local_lv_tree.iter().map(|(k, v)| *v).collect();
-                let full_metric_name = format!("{metric_name}_count");
-                let outgoing_gauge = get_or_register_metric(full_metric_name, &ordered_labels).await;
-                let m = outgoing_gauge.with_label_values(&ordered_values);
-                m.set_timestamp_ms(incoming_metric.timestamp as i64);
-                m.set(incoming_metric.value.count.unwrap() as f64);
-            }
+/// Renders converted series in the text exposition format, grouped by metric name, with the
+/// cloudwatch timestamp on every sample.
+pub fn series_to_text(series: &[TimeSeries]) -> String {
+    let mut by_name: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for s in series {
+        let Some(name) = metric_name(s) else {
+            continue;
+        };
+        let labels = s
+            .labels
+            .iter()
+            .filter(|l| l.name != METRIC_NAME_LABEL)
+            .map(|l| {
+                let value = l.value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+                format!("{}=\"{value}\"", l.name)
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        let series_name = match labels.is_empty() {
+            true => name.to_string(),
+            false => format!("{name}{{{labels}}}"),
+        };
+        let lines = by_name.entry(name).or_default();
+        for sample in s.samples.iter() {
+            lines.push(format!("{series_name} {} {}", sample.value, sample.timestamp));
         }
-        // MetricUnit::Count => {
-        //     warn!("Received a count -- need to implement this")
-        // }
-        MetricUnit::Unknown => {
-            warn!("Received unknown metric, {:#?}", incoming_metric.clone());
+    }
+    let mut text = String::new();
+    for (name, mut lines) in by_name {
+        lines.sort();
+        text.push_str(&format!("# TYPE {name} gauge\n"));
+        for line in lines {
+            text.push_str(&line);
+            text.push('\n');
         }
     }
-    Ok(())
+    text
 }
 
 pub fn sanitize_metric_name(input: String) -> String {
@@ -249,23 +420,51 @@ pub fn sanitize_metric_name(input: String) -> String {
         .collect::<String>()
 }
 
-pub async fn get_or_register_metric(metric_name: String, ordered_labels: &Vec<&str>) -> GaugeVec {
-    let mut recorder = GAUGES.lock().await;
-    match recorder.get(&metric_name) {
-        None => {
-            let gv = register_gauge_vec!(
-                        app_opts!(
-                            metric_name.clone(),
-                            "autogenerated metric from firehose"
-                        ),
-                        &ordered_labels
-                    )
-                .unwrap();
-            recorder.insert(metric_name.clone(), gv.clone());
-            gv
-        }
-        Some(m) => {
-            m.clone()
-        }
-    }
+#[test]
+fn test_to_timeseries() {
+    use crate::structs::{DimensionMap, MetricValue};
+    let metric = CloudWatchMetric {
+        metric_stream_name: "my-stream".to_string(),
+        account_id: "123456789012".to_string(),
+        region: "us-east-1".to_string(),
+        namespace: "AWS/EC2".to_string(),
+        metric_name: "CPUUtilization".to_string(),
+        dimensions: DimensionMap([("InstanceId".to_string(), "i-0123".to_string())].into()),
+        timestamp: 1_700_000_060_123,
+        value: MetricValue {
+            max: Some(0.1234567891234),
+            additional: [("p99".to_string(), 0.5)].into(),
+            ..Default::default()
+        },
+        unit: MetricUnit::Percent,
+    };
+    let series = to_timeseries(&metric, &MetricNamer::default());
+    assert_eq!(series.len(), 2);
+    let max = &series[0];
+    let names: Vec<&str> = max.labels.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(names, vec!["__name__", "account_id", "instance_id", "metric_stream_name", "region"]);
+    assert_eq!(max.labels[0].value, "firehose_ec2_cpuutilization_percent_max");
+    // no precision lost along the way
+    assert_eq!(max.samples[0].value, 0.1234567891234);
+    assert_eq!(max.samples[0].timestamp, 1_700_000_060_123);
+    let p99 = &series[1];
+    assert_eq!(p99.labels[0].value, "firehose_ec2_cpuutilization_percent");
+    assert!(p99.labels.iter().any(|l| l.name == "quantile" && l.value == "0.99"));
+}
+
+#[test]
+fn test_series_to_text() {
+    let series = vec![
+        new_series(
+            "b_max".to_string(),
+            BTreeMap::from([("region".to_string(), "us-\"east\"-1".to_string())]),
+            1_000,
+            2.5,
+        ),
+        new_series("a_min".to_string(), BTreeMap::new(), 2_000, 1.0),
+    ];
+    assert_eq!(
+        series_to_text(&series),
+        "# TYPE a_min gauge\na_min 1 2000\n# TYPE b_max gauge\nb_max{region=\"us-\\\"east\\\"-1\"} 2.5 1000\n"
+    );
 }
@@ -0,0 +1,253 @@
+use md5::{Digest, Md5};
+use prometheus_remote_write::Label;
+use regex::Regex;
+use serde::Deserialize;
+
+/// One step of a `relabel_configs` list, with the same fields and defaults as prometheus.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelabelConfig {
+    pub source_labels: Vec<String>,
+    pub separator: String,
+    pub target_label: String,
+    pub regex: String,
+    pub modulus: u64,
+    pub replacement: String,
+    pub action: RelabelAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelabelAction {
+    #[default]
+    Replace,
+    Keep,
+    Drop,
+    HashMod,
+    LabelMap,
+    LabelDrop,
+    LabelKeep,
+    Lowercase,
+    Uppercase,
+}
+
+impl Default for RelabelConfig {
+    fn default() -> Self {
+        RelabelConfig {
+            source_labels: vec![],
+            separator: ";".to_string(),
+            target_label: String::new(),
+            regex: "(.*)".to_string(),
+            modulus: 0,
+            replacement: "$1".to_string(),
+            action: RelabelAction::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    config: RelabelConfig,
+    regex: Regex,
+}
+
+/// A compiled `relabel_configs` list.  Empty unless configured, in which case it leaves every
+/// series as it is.
+#[derive(Debug, Clone, Default)]
+pub struct Relabeler {
+    rules: Vec<Rule>,
+}
+
+impl Relabeler {
+    pub fn new(configs: &[RelabelConfig]) -> anyhow::Result<Self> {
+        let mut rules = vec![];
+        for (i, config) in configs.iter().enumerate() {
+            // like prometheus, the regex has to match the whole value
+            let regex = Regex::new(&format!("^(?:{})$", config.regex))
+                .map_err(|e| anyhow!("relabel_configs[{i}]: invalid regex {:?}: {e}", config.regex))?;
+            let needs_target = matches!(
+                config.action,
+                RelabelAction::Replace | RelabelAction::HashMod | RelabelAction::Lowercase | RelabelAction::Uppercase
+            );
+            if needs_target && config.target_label.is_empty() {
+                bail!("relabel_configs[{i}]: {:?} needs a target_label", config.action);
+            }
+            if config.action == RelabelAction::HashMod && config.modulus == 0 {
+                bail!("relabel_configs[{i}]: hashmod needs a modulus of at least 1");
+            }
+            rules.push(Rule {
+                config: config.clone(),
+                regex,
+            });
+        }
+        Ok(Relabeler { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Runs every rule over a series' labels, `__name__` included.  `None` means the series was
+    /// dropped.  What comes back is sorted by name and has no empty values, as remote write wants.
+    pub fn apply(&self, labels: Vec<Label>) -> Option<Vec<Label>> {
+        let mut labels = labels;
+        for rule in self.rules.iter() {
+            if !rule.apply(&mut labels) {
+                return None;
+            }
+        }
+        labels.retain(|l| !l.value.is_empty());
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+        Some(labels)
+    }
+}
+
+fn get<'a>(labels: &'a [Label], name: &str) -> &'a str {
+    labels
+        .iter()
+        .find(|l| l.name == name)
+        .map_or("", |l| l.value.as_str())
+}
+
+/// Setting a label to the empty string removes it.
+fn set(labels: &mut Vec<Label>, name: &str, value: String) {
+    labels.retain(|l| l.name != name);
+    if !value.is_empty() {
+        labels.push(Label {
+            name: name.to_string(),
+            value,
+        });
+    }
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+impl Rule {
+    /// Returns false if the series is to be dropped.
+    fn apply(&self, labels: &mut Vec<Label>) -> bool {
+        let config = &self.config;
+        let value = config
+            .source_labels
+            .iter()
+            .map(|name| get(labels, name))
+            .collect::<Vec<&str>>()
+            .join(&config.separator);
+        match config.action {
+            RelabelAction::Keep => return self.regex.is_match(&value),
+            RelabelAction::Drop => return !self.regex.is_match(&value),
+            RelabelAction::Replace => {
+                let Some(captures) = self.regex.captures(&value) else {
+                    return true;
+                };
+                let mut target = String::new();
+                captures.expand(&config.target_label, &mut target);
+                if !is_valid_label_name(&target) {
+                    return true;
+                }
+                let mut replaced = String::new();
+                captures.expand(&config.replacement, &mut replaced);
+                set(labels, &target, replaced);
+            }
+            RelabelAction::HashMod => {
+                let hash = Md5::digest(value.as_bytes());
+                let tail: [u8; 8] = hash[8..].try_into().unwrap_or_default();
+                let modulo = u64::from_be_bytes(tail) % config.modulus;
+                set(labels, &config.target_label, modulo.to_string());
+            }
+            RelabelAction::Lowercase => set(labels, &config.target_label, value.to_lowercase()),
+            RelabelAction::Uppercase => set(labels, &config.target_label, value.to_uppercase()),
+            RelabelAction::LabelMap => {
+                let mapped: Vec<(String, String)> = labels
+                    .iter()
+                    .filter_map(|l| {
+                        let captures = self.regex.captures(&l.name)?;
+                        let mut name = String::new();
+                        captures.expand(&config.replacement, &mut name);
+                        Some((name, l.value.clone()))
+                    })
+                    .collect();
+                for (name, value) in mapped {
+                    set(labels, &name, value);
+                }
+            }
+            RelabelAction::LabelDrop => labels.retain(|l| !self.regex.is_match(&l.name)),
+            RelabelAction::LabelKeep => labels.retain(|l| self.regex.is_match(&l.name)),
+        }
+        true
+    }
+}
+
+#[test]
+fn test_relabel() {
+    let labels = |pairs: &[(&str, &str)]| -> Vec<Label> {
+        pairs
+            .iter()
+            .map(|(name, value)| Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    };
+    let series = labels(&[
+        ("__name__", "firehose_ec2_cpuutilization_percent_max"),
+        ("account_id", "111111111111"),
+        ("auto_scaling_group_name", "web"),
+        ("metric_stream_name", "all"),
+        ("region", "us-east-1"),
+    ]);
+    let relabeler = Relabeler::new(&serde_yaml::from_str::<Vec<RelabelConfig>>(
+        r#"
+- source_labels: [__name__]
+  regex: firehose_(.*)
+  target_label: __name__
+  replacement: aws_$1
+- source_labels: [account_id, region]
+  target_label: location
+  replacement: $1/$2
+  regex: (\d+);(.*)
+- regex: auto_scaling_(.*)
+  action: labelmap
+  replacement: asg_$1
+- regex: auto_scaling_group_name|metric_stream_name
+  action: labeldrop
+- source_labels: [asg_group_name]
+  target_label: asg_group_name
+  action: uppercase
+- source_labels: [account_id]
+  target_label: shard
+  modulus: 4
+  action: hashmod
+"#,
+    )
+    .unwrap())
+    .unwrap();
+    let relabeled = relabeler.apply(series.clone()).unwrap();
+    let pairs: Vec<(&str, &str)> = relabeled.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect();
+    assert_eq!(&pairs[..3], &[
+        ("__name__", "aws_ec2_cpuutilization_percent_max"),
+        ("account_id", "111111111111"),
+        ("asg_group_name", "WEB"),
+    ]);
+    assert_eq!(&pairs[3..5], &[("location", "111111111111/us-east-1"), ("region", "us-east-1")]);
+    assert_eq!(pairs[5].0, "shard");
+    assert!(pairs[5].1.parse::<u64>().unwrap() < 4);
+
+    let keep = Relabeler::new(&[RelabelConfig {
+        source_labels: vec!["region".to_string()],
+        regex: "eu-.*".to_string(),
+        action: RelabelAction::Keep,
+        ..Default::default()
+    }])
+    .unwrap();
+    assert!(keep.apply(series).is_none());
+    assert!(Relabeler::new(&[RelabelConfig {
+        action: RelabelAction::HashMod,
+        target_label: "shard".to_string(),
+        ..Default::default()
+    }])
+    .is_err());
+}
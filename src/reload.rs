@@ -0,0 +1,212 @@
+use crate::access_key::load_access_keys;
+use crate::config::{env_var, read_file, Config, TlsConfig};
+use crate::flusher::{spawn_flusher, FlusherSettings};
+use crate::naming::MetricNamer;
+use crate::prometheus::{CONFIG_GENERATION, CONFIG_RELOADS};
+use crate::relabel::Relabeler;
+use crate::structs::{Pipeline, SharedState};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+
+/// Owns turning config into the running pipeline, both at startup and on every reload after.
+pub struct Reloader {
+    path: Option<PathBuf>,
+    listen_override: Option<String>,
+    state: SharedState,
+    /// The latest flusher task per destination name, including ones that have been removed from
+    /// the config but may still be draining.
+    flushers: HashMap<String, JoinHandle<()>>,
+    /// The listeners started with, and the body limit they enforce, which a reload can't change.
+    listeners: Option<(String, Option<TlsConfig>, usize)>,
+    loaded: Option<String>,
+}
+
+impl Reloader {
+    pub fn new(path: Option<PathBuf>, listen_override: Option<String>, state: SharedState) -> Self {
+        Reloader {
+            path,
+            listen_override,
+            state,
+            flushers: HashMap::new(),
+            listeners: None,
+            loaded: None,
+        }
+    }
+
+    /// Reads the config file and environment, applies the command line, and validates the result.
+    pub fn load(&mut self) -> anyhow::Result<Config> {
+        // read once, so that what's applied is exactly what the watcher compares against
+        self.loaded = match self.path.as_ref() {
+            Some(path) => Some(read_file(path)?),
+            None => None,
+        };
+        let mut config = Config::from_file(self.path.as_deref().zip(self.loaded.as_deref()), &env_var)?;
+        if let Some(listen) = self.listen_override.as_ref() {
+            config.listen = listen.clone();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Builds a pipeline from `config` and swaps it in.  Unchanged destinations keep their
+    /// flusher; those of changed and removed ones stop once the old pipeline is done with.
+    pub async fn apply(&mut self, config: &Config) -> anyhow::Result<()> {
+        let access_keys = load_access_keys(&config.access_keys, config.access_keys_file.as_deref())?;
+        let previous = self.state.read().await.pipeline.clone();
+
+        // build new flushers' settings first, so that a destination that can't be set up fails
+        // the reload before any running flusher is touched
+        let mut new_settings: HashMap<&str, FlusherSettings> = HashMap::new();
+        for destination_config in config
+            .destinations
+            .iter()
+            .filter(|dc| !previous.destinations.iter().any(|d| &d.config == *dc))
+        {
+            let settings = FlusherSettings::from_config(destination_config)
+                .map_err(|e| anyhow!("Couldn't set up destination {}: {e}", destination_config.name))?;
+            new_settings.insert(&destination_config.name, settings);
+        }
+
+        let mut destinations = vec![];
+        for destination_config in config.destinations.iter() {
+            let name = &destination_config.name;
+            match previous.destinations.iter().find(|d| &d.name == name) {
+                Some(d) if &d.config == destination_config => destinations.push(d.clone()),
+                existing => {
+                    match existing {
+                        Some(_) => info!("Settings for destination {name} changed, restarting its flusher."),
+                        None => info!("Forwarding metrics to {name} at {}", destination_config.write_url()),
+                    }
+                    let settings = new_settings
+                        .remove(name.as_str())
+                        .expect("settings are built for every new or changed destination");
+                    let (destination, handle) =
+                        spawn_flusher(settings, destination_config, self.flushers.remove(name));
+                    self.flushers.insert(name.clone(), handle);
+                    destinations.push(destination);
+                }
+            }
+        }
+        for removed in previous
+            .destinations
+            .iter()
+            .filter(|d| !destinations.iter().any(|n| n.name == d.name))
+        {
+            info!("Destination {} was removed, it will stop once drained.", removed.name);
+        }
+        match self.listeners.as_ref() {
+            Some((listen, _, _)) if listen != &config.listen => {
+                warn!("listen changed from {listen} to {}, that needs a restart to take effect.", config.listen);
+            }
+            Some((_, tls, _)) if tls != &config.tls => {
+                warn!("tls settings changed, that needs a restart to take effect.");
+            }
+            Some((_, _, limit)) if *limit < config.max_decompressed_bytes => {
+                warn!("max_decompressed_bytes was raised, bodies over {limit} bytes are refused until a restart.");
+            }
+            Some(_) => {}
+            None => {
+                self.listeners = Some((config.listen.clone(), config.tls.clone(), config.max_decompressed_bytes))
+            }
+        }
+
+        let pipeline = Pipeline {
+            access_keys,
+            max_decompressed_bytes: config.max_decompressed_bytes,
+            metric_stream_format: config.metric_stream_format,
+            naming: MetricNamer::new(&config.naming)?,
+            filters: config.filters.clone(),
+            relabel: Relabeler::new(&config.relabel_configs)?,
+            destinations,
+        };
+        let mut state = self.state.write().await;
+        state.pipeline = Arc::new(pipeline);
+        state.generation += 1;
+        CONFIG_GENERATION
+            .with_label_values(&[])
+            .set(state.generation as f64);
+        Ok(())
+    }
+
+    async fn reload(&mut self) {
+        let result = match self.load() {
+            Ok(config) => self.apply(&config).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => {
+                let generation = self.state.read().await.generation;
+                info!("Reloaded config, now at generation {generation}.");
+                CONFIG_RELOADS.with_label_values(&["success"]).inc();
+            }
+            Err(e) => {
+                error!("Config reload failed, carrying on with the current config: {e}");
+                CONFIG_RELOADS.with_label_values(&["failure"]).inc();
+            }
+        }
+    }
+
+    /// Reloads on SIGHUP, and whenever the config file's contents change.  The file is polled
+    /// rather than watched, which copes with the symlink swaps k8s does for mounted configmaps.
+    pub async fn watch(mut self, interval: Duration) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                error!("Couldn't listen for SIGHUP, config will only reload on file changes: {e}");
+                None
+            }
+        };
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                Some(_) = async { hangup.as_mut()?.recv().await } => {
+                    info!("Got SIGHUP, reloading config.");
+                }
+                _ = ticker.tick() => {
+                    let Some(path) = self.path.as_ref() else {
+                        continue;
+                    };
+                    let contents = fs::read_to_string(path).ok();
+                    if contents.is_none() || contents == self.loaded {
+                        continue;
+                    }
+                    info!("Config file {path:?} changed, reloading.");
+                }
+            }
+            self.reload().await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_apply_keeps_unchanged_destinations() {
+    let config = |staging_timeout: u64| {
+        let raw = format!(
+            r#"
+destinations:
+  - name: mimir
+    addr: http://mimir:8080
+  - name: staging
+    addr: http://victoria:8428
+    timeout_secs: {staging_timeout}
+"#
+        );
+        Config::parse_with(&raw, env_var).unwrap()
+    };
+    let state = SharedState::default();
+    let mut reloader = Reloader::new(None, None, Arc::clone(&state));
+    reloader.apply(&config(10)).await.unwrap();
+    let first = state.read().await.pipeline.clone();
+
+    reloader.apply(&config(20)).await.unwrap();
+    let second = state.read().await.pipeline.clone();
+    assert_eq!(state.read().await.generation, 2);
+    assert!(first.destinations[0].sender.same_channel(&second.destinations[0].sender));
+    assert!(!first.destinations[1].sender.same_channel(&second.destinations[1].sender));
+    assert_eq!(second.destinations[1].config.timeout_secs, 20);
+}
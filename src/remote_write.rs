@@ -0,0 +1,208 @@
+use crate::batch::MetricBatch;
+use crate::structs::MetricUnit;
+use prometheus_remote_write::{TimeSeries, WriteRequest};
+use prost::Message;
+use std::collections::HashMap;
+use strum::{Display, EnumString};
+
+pub const METRIC_NAME_LABEL: &str = "__name__";
+pub const HEADER_VERSION: &str = "X-Prometheus-Remote-Write-Version";
+pub const HEADER_SAMPLES_WRITTEN: &str = "X-Prometheus-Remote-Write-Samples-Written";
+pub const HEADER_HISTOGRAMS_WRITTEN: &str = "X-Prometheus-Remote-Write-Histograms-Written";
+pub const HEADER_EXEMPLARS_WRITTEN: &str = "X-Prometheus-Remote-Write-Exemplars-Written";
+
+/// Which remote write message a destination is sent.  2.0 interns label strings and carries
+/// metadata inline; 1.0 is what every receiver understands.
+#[derive(Default, Debug, Clone, Copy, PartialEq, EnumString, Display)]
+pub enum RemoteWriteProtocol {
+    #[default]
+    #[strum(serialize = "1.0", serialize = "v1")]
+    V1,
+    #[strum(serialize = "2.0", serialize = "v2")]
+    V2,
+}
+
+impl RemoteWriteProtocol {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            RemoteWriteProtocol::V1 => "application/x-protobuf",
+            RemoteWriteProtocol::V2 => "application/x-protobuf;proto=io.prometheus.write.v2.Request",
+        }
+    }
+
+    pub fn version(&self) -> &'static str {
+        match self {
+            RemoteWriteProtocol::V1 => "0.1.0",
+            RemoteWriteProtocol::V2 => "2.0.0",
+        }
+    }
+
+    /// Snappy-compressed protobuf, ready to POST.
+    pub fn encode(&self, batch: MetricBatch) -> anyhow::Result<Vec<u8>> {
+        match self {
+            RemoteWriteProtocol::V1 => Ok(WriteRequest {
+                timeseries: batch.into_series(),
+            }
+            .encode_compressed()?),
+            RemoteWriteProtocol::V2 => {
+                let units = batch.units().clone();
+                let request = v2::Request::from_series(batch.into_series(), &units);
+                Ok(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?)
+            }
+        }
+    }
+}
+
+/// `io.prometheus.write.v2.Request` and friends.  Native histograms and exemplars are left out,
+/// cloudwatch has neither.
+pub mod v2 {
+    use super::*;
+    use crate::batch::metric_name;
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Request {
+        #[prost(string, repeated, tag = "4")]
+        pub symbols: Vec<String>,
+        #[prost(message, repeated, tag = "5")]
+        pub timeseries: Vec<TimeSeries>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct TimeSeries {
+        #[prost(uint32, repeated, tag = "1")]
+        pub labels_refs: Vec<u32>,
+        #[prost(message, repeated, tag = "2")]
+        pub samples: Vec<Sample>,
+        #[prost(message, optional, tag = "5")]
+        pub metadata: Option<Metadata>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Sample {
+        #[prost(double, tag = "1")]
+        pub value: f64,
+        #[prost(int64, tag = "2")]
+        pub timestamp: i64,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Metadata {
+        #[prost(enumeration = "MetricType", tag = "1")]
+        pub r#type: i32,
+        #[prost(uint32, tag = "3")]
+        pub help_ref: u32,
+        #[prost(uint32, tag = "4")]
+        pub unit_ref: u32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MetricType {
+        Unspecified = 0,
+        Counter = 1,
+        Gauge = 2,
+        Histogram = 3,
+        GaugeHistogram = 4,
+        Summary = 5,
+        Info = 6,
+        StateSet = 7,
+    }
+
+    /// Builds the symbol table as it goes.  The spec requires the empty string at index 0, which
+    /// also doubles as "not set" for the metadata refs.
+    #[derive(Debug)]
+    struct SymbolTable {
+        symbols: Vec<String>,
+        index: HashMap<String, u32>,
+    }
+
+    impl SymbolTable {
+        fn new() -> Self {
+            SymbolTable {
+                symbols: vec![String::new()],
+                index: HashMap::from([(String::new(), 0)]),
+            }
+        }
+
+        fn intern(&mut self, s: &str) -> u32 {
+            if let Some(i) = self.index.get(s) {
+                return *i;
+            }
+            let i = self.symbols.len() as u32;
+            self.symbols.push(s.to_string());
+            self.index.insert(s.to_string(), i);
+            i
+        }
+    }
+
+    impl Request {
+        pub fn from_series(series: Vec<super::TimeSeries>, units: &HashMap<String, MetricUnit>) -> Self {
+            let mut symbols = SymbolTable::new();
+            let help_ref = symbols.intern("autogenerated metric from firehose");
+            let mut timeseries: Vec<TimeSeries> = Vec::with_capacity(series.len());
+            for mut s in series {
+                let unit_ref = match metric_name(&s).and_then(|name| units.get(name)) {
+                    Some(MetricUnit::Unknown | MetricUnit::None) | None => 0,
+                    Some(unit) => symbols.intern(&unit.to_string()),
+                };
+                // 2.0 requires labels sorted by name, with __name__ among them
+                s.labels.sort_by(|a, b| a.name.cmp(&b.name));
+                let mut labels_refs: Vec<u32> = Vec::with_capacity(s.labels.len() * 2);
+                for label in s.labels.iter() {
+                    labels_refs.push(symbols.intern(&label.name));
+                    labels_refs.push(symbols.intern(&label.value));
+                }
+                timeseries.push(TimeSeries {
+                    labels_refs,
+                    samples: s
+                        .samples
+                        .iter()
+                        .map(|sample| Sample {
+                            value: sample.value,
+                            timestamp: sample.timestamp,
+                        })
+                        .collect(),
+                    // everything forwarded from cloudwatch is a point-in-time gauge
+                    metadata: Some(Metadata {
+                        r#type: MetricType::Gauge as i32,
+                        help_ref,
+                        unit_ref,
+                    }),
+                });
+            }
+            Request {
+                symbols: symbols.symbols,
+                timeseries,
+            }
+        }
+    }
+}
+
+#[test]
+fn test_v2_interns_symbols() {
+    use prometheus_remote_write::{Label, Sample};
+    let series = |name: &str| TimeSeries {
+        labels: vec![
+            Label {
+                name: "region".to_string(),
+                value: "us-east-1".to_string(),
+            },
+            Label {
+                name: METRIC_NAME_LABEL.to_string(),
+                value: name.to_string(),
+            },
+        ],
+        samples: vec![Sample {
+            value: 1.5,
+            timestamp: 1_700_000_000_000,
+        }],
+    };
+    let request = v2::Request::from_series(vec![series("a_max"), series("a_min")], &HashMap::new());
+    assert_eq!(request.symbols[0], "");
+    // "region" and "us-east-1" are only stored once
+    assert_eq!(request.symbols.iter().filter(|s| *s == "us-east-1").count(), 1);
+    let first = &request.timeseries[0];
+    assert_eq!(request.symbols[first.labels_refs[0] as usize], METRIC_NAME_LABEL);
+    assert_eq!(request.symbols[first.labels_refs[1] as usize], "a_max");
+    assert_eq!(first.samples[0].timestamp, 1_700_000_000_000);
+}
@@ -0,0 +1,107 @@
+use crate::config::RetryConfig;
+use crate::flusher::FlusherSettings;
+use crate::prometheus::{send_write_request, WriteError, REMOTE_WRITE_GIVE_UPS, REMOTE_WRITE_RETRIES};
+use crate::remote_write::RemoteWriteProtocol;
+use crate::tenant::Tenant;
+use axum::body::Bytes;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// How hard to try before a batch is dropped, following the remote write spec: 5xx and 429 are
+/// retried with exponential backoff, any other 4xx is not.
+#[derive(Debug, Clone)]
+pub struct RetrySettings {
+    pub(crate) max_attempts: u32,
+    pub(crate) min_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+    pub(crate) max_age: Duration,
+}
+
+impl RetrySettings {
+    pub fn from_config(config: &RetryConfig) -> Self {
+        RetrySettings {
+            max_attempts: config.max_attempts,
+            min_backoff: Duration::from_millis(config.min_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            max_age: Duration::from_secs(config.max_age_secs),
+        }
+    }
+
+    /// Doubles from `min_backoff` up to `max_backoff`, then picks somewhere in the upper half of
+    /// that so that replicas recovering from the same outage don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .min_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let half = ceiling / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendOutcome {
+    Delivered,
+    /// The receiver refused the batch for good, so there's no point holding on to it.
+    Rejected,
+    /// The receiver is unhealthy and the retry budget ran out; the batch may succeed later.
+    GaveUp,
+}
+
+/// Sends an encoded write request until it is accepted, rejected outright, or the retry budget
+/// runs out.
+pub async fn send_with_retry(
+    destination: &FlusherSettings,
+    body: Bytes,
+    protocol: RemoteWriteProtocol,
+    tenant: Option<&Tenant>,
+) -> SendOutcome {
+    let name = destination.name.as_str();
+    let settings = &destination.retry;
+    let started = Instant::now();
+    let mut attempt: u32 = 0;
+    loop {
+        let (reason, retry_after) = match send_write_request(destination, body.clone(), protocol, tenant).await {
+            Ok(_) => return SendOutcome::Delivered,
+            Err(WriteError::Permanent(reason)) => {
+                error!("Remote write to {name} rejected, not retrying: {reason}");
+                REMOTE_WRITE_GIVE_UPS.with_label_values(&[name, "non_retryable"]).inc();
+                return SendOutcome::Rejected;
+            }
+            Err(WriteError::Retryable { reason, retry_after }) => (reason, retry_after),
+        };
+        attempt += 1;
+        if attempt >= settings.max_attempts {
+            error!("Remote write to {name} failed after {attempt} attempts, giving up on batch: {reason}");
+            REMOTE_WRITE_GIVE_UPS.with_label_values(&[name, "max_attempts"]).inc();
+            return SendOutcome::GaveUp;
+        }
+        let delay = retry_after.unwrap_or_else(|| settings.backoff(attempt - 1));
+        if started.elapsed() + delay > settings.max_age {
+            error!(
+                "Remote write to {name} still failing after {:?}, giving up on batch: {reason}",
+                started.elapsed()
+            );
+            REMOTE_WRITE_GIVE_UPS.with_label_values(&[name, "max_age"]).inc();
+            return SendOutcome::GaveUp;
+        }
+        warn!("Remote write attempt {attempt} to {name} failed, retrying in {delay:?}: {reason}");
+        REMOTE_WRITE_RETRIES.with_label_values(&[name]).inc();
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[test]
+fn test_backoff_is_capped() {
+    let settings = RetrySettings {
+        max_attempts: 10,
+        min_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_secs(1),
+        max_age: Duration::from_secs(60),
+    };
+    for attempt in 0..20 {
+        let ceiling = Duration::from_millis(100 * 2u64.pow(attempt.min(10))).min(Duration::from_secs(1));
+        let delay = settings.backoff(attempt);
+        assert!(delay >= ceiling / 2 && delay <= ceiling, "{attempt}: {delay:?}");
+    }
+}
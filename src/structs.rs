@@ -1,14 +1,22 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::config::FilterConfig;
+use crate::flusher::Destination;
+use crate::naming::MetricNamer;
+use crate::relabel::Relabeler;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::sync::Arc;
-use strum::Display;
+use strum::{Display, EnumString};
 use tokio::sync::RwLock;
-use tracing_subscriber::registry::Data;
 use convert_case::{Case, Casing};
 
 pub type SharedState = Arc<RwLock<AppState>>;
 #[derive(Default, Debug, Deserialize, Clone)]
-pub struct DimensionMap(HashMap<String, String>);
+pub struct DimensionMap(pub(crate) HashMap<String, String>);
 
 #[derive(Default, Debug, Deserialize, Clone)]
 pub struct LabelsValues {
@@ -18,8 +26,37 @@ pub struct LabelsValues {
 
 #[derive(Default)]
 pub struct AppState {
-    // leaving this unimplemented for now
-    _string: Option<String>,
+    pub(crate) pipeline: Arc<Pipeline>,
+    /// Bumped every time a config is applied, the first one being generation 1.
+    pub(crate) generation: u64,
+}
+
+/// Everything a delivery is processed with.  A reload swaps in a whole new pipeline, and each
+/// delivery holds on to the one it started with, so it finishes under consistent settings.
+#[derive(Default, Debug)]
+pub struct Pipeline {
+    pub(crate) access_keys: Vec<String>,
+    pub(crate) max_decompressed_bytes: usize,
+    pub(crate) metric_stream_format: MetricStreamFormat,
+    pub(crate) naming: MetricNamer,
+    pub(crate) filters: FilterConfig,
+    pub(crate) relabel: Relabeler,
+    pub(crate) destinations: Vec<Destination>,
+}
+
+/// The output format picked on the CloudWatch metric stream.  `Auto` sniffs each record.
+#[derive(Default, Debug, Clone, Copy, PartialEq, EnumString, Display)]
+#[strum(ascii_case_insensitive)]
+pub enum MetricStreamFormat {
+    #[default]
+    #[strum(serialize = "auto")]
+    Auto,
+    #[strum(serialize = "json")]
+    Json,
+    #[strum(serialize = "opentelemetry1.0", serialize = "otlp1")]
+    OpenTelemetry1,
+    #[strum(serialize = "opentelemetry0.7", serialize = "otlp07")]
+    OpenTelemetry07,
 }
 #[derive(Default, Deserialize)]
 pub struct FirehoseData {
@@ -37,6 +74,34 @@ pub struct Firehose {
     pub(crate) timestamp: Option<u64>,
 }
 
+/// The body we hand back to Firehose, as described in the HTTP endpoint delivery spec.  Firehose
+/// uses `requestId` and `timestamp` to correlate deliveries, and logs `errorMessage` on failures.
+#[derive(Default, Serialize, Debug)]
+pub struct FirehoseResponse {
+    #[serde(skip)]
+    pub(crate) status: StatusCode,
+    #[serde(rename = "requestId")]
+    pub(crate) request_id: String,
+    pub(crate) timestamp: u64,
+    #[serde(rename = "errorMessage", skip_serializing_if = "Option::is_none")]
+    pub(crate) error_message: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum FirehoseError {
+    InvalidJson(String),
+    InvalidBase64(String),
+    InvalidUtf8(String),
+    InvalidProtobuf(String),
+    QueueFull(String),
+    MissingAccessKey,
+    InvalidAccessKey,
+    InvalidGzip(String),
+    PayloadTooLarge(usize),
+    PartialFailure { failed: usize, total: usize },
+    NothingDecoded { total: usize },
+}
+
 #[derive(Default, Deserialize, Debug, Clone)]
 pub struct CloudWatchMetric {
     pub(crate) metric_stream_name: String,
@@ -52,13 +117,41 @@ pub struct CloudWatchMetric {
 #[derive(Default, Deserialize, Debug, Clone)]
 pub struct MetricValue {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) max: Option<f32>,
+    pub(crate) max: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) min: Option<f32>,
+    pub(crate) min: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) sum: Option<f32>,
+    pub(crate) sum: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) count: Option<f32>,
+    pub(crate) count: Option<f64>,
+    /// Any additional statistics configured on the metric stream, e.g. `p99`, `tm99` or `IQM`,
+    /// keyed by the name CloudWatch sends.
+    #[serde(flatten, deserialize_with = "numeric_statistics")]
+    pub(crate) additional: BTreeMap<String, f64>,
+}
+
+/// Keeps the extra fields of a `value` that are numbers, so that one of another kind doesn't
+/// fail the whole record.
+fn numeric_statistics<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, f64>, D::Error> {
+    let fields = BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?;
+    Ok(fields
+        .into_iter()
+        .filter_map(|(name, value)| match value.as_f64() {
+            Some(v) => Some((name, v)),
+            None => {
+                debug!("Skipping non-numeric statistic {name}: {value}");
+                None
+            }
+        })
+        .collect())
+}
+
+/// How an additional statistic gets exported: percentiles become a `quantile` label on the
+/// summary-style series, everything else gets a series of its own.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statistic {
+    Percentile(f64),
+    Other(String),
 }
 
 #[derive(Default, Deserialize, Debug, Clone, Display)]
@@ -108,3 +201,145 @@ impl DimensionMap {
         }
 
 }
+
+impl FirehoseError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            FirehoseError::InvalidJson(_)
+            | FirehoseError::InvalidBase64(_)
+            | FirehoseError::InvalidUtf8(_)
+            | FirehoseError::InvalidProtobuf(_)
+            | FirehoseError::InvalidGzip(_)
+            | FirehoseError::NothingDecoded { .. } => StatusCode::BAD_REQUEST,
+            FirehoseError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            // the good records were forwarded and firehose retrying won't fix the corrupt ones,
+            // so acknowledge the delivery and leave the details in errorMessage
+            FirehoseError::PartialFailure { .. } => StatusCode::OK,
+            // a 5xx tells firehose to back off and retry the delivery later
+            FirehoseError::QueueFull(_) => StatusCode::SERVICE_UNAVAILABLE,
+            FirehoseError::MissingAccessKey => StatusCode::UNAUTHORIZED,
+            FirehoseError::InvalidAccessKey => StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// A short, bounded label value for self-metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            FirehoseError::InvalidJson(_) => "json",
+            FirehoseError::InvalidBase64(_) => "base64",
+            FirehoseError::InvalidUtf8(_) => "utf8",
+            FirehoseError::InvalidProtobuf(_) => "protobuf",
+            FirehoseError::QueueFull(_) => "queue_full",
+            FirehoseError::MissingAccessKey => "missing_access_key",
+            FirehoseError::InvalidAccessKey => "invalid_access_key",
+            FirehoseError::InvalidGzip(_) => "gzip",
+            FirehoseError::PayloadTooLarge(_) => "too_large",
+            FirehoseError::PartialFailure { .. } => "partial_failure",
+            FirehoseError::NothingDecoded { .. } => "nothing_decoded",
+        }
+    }
+}
+
+impl fmt::Display for FirehoseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirehoseError::InvalidJson(e) => write!(f, "Unable to parse request body as JSON: {e}"),
+            FirehoseError::InvalidBase64(e) => write!(f, "Unable to base64-decode record data: {e}"),
+            FirehoseError::InvalidUtf8(e) => write!(f, "Record data is not valid UTF-8: {e}"),
+            FirehoseError::InvalidProtobuf(e) => {
+                write!(f, "Unable to decode OpenTelemetry record: {e}")
+            }
+            FirehoseError::QueueFull(e) => write!(f, "Unable to queue metrics for remote write: {e}"),
+            FirehoseError::MissingAccessKey => write!(f, "Missing X-Amz-Firehose-Access-Key header"),
+            FirehoseError::InvalidAccessKey => write!(f, "Invalid X-Amz-Firehose-Access-Key"),
+            FirehoseError::InvalidGzip(e) => write!(f, "Unable to gunzip request body: {e}"),
+            FirehoseError::PayloadTooLarge(limit) => {
+                write!(f, "Decompressed request body exceeds {limit} bytes")
+            }
+            FirehoseError::PartialFailure { failed, total } => {
+                write!(f, "{failed} of {total} records could not be decoded and were dropped")
+            }
+            FirehoseError::NothingDecoded { total } => write!(f, "None of the {total} records could be decoded"),
+        }
+    }
+}
+
+impl FirehoseResponse {
+    pub fn ok(request_id: String) -> Self {
+        FirehoseResponse {
+            status: StatusCode::OK,
+            request_id,
+            timestamp: now_ms(),
+            error_message: None,
+        }
+    }
+    pub fn error(request_id: String, err: FirehoseError) -> Self {
+        FirehoseResponse {
+            status: err.status_code(),
+            request_id,
+            timestamp: now_ms(),
+            error_message: Some(err.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for FirehoseResponse {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl Statistic {
+    pub fn parse(name: &str) -> Statistic {
+        // only p<digits>[.<digits>], so that e.g. p1e2 or pNaN aren't taken for percentiles
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if let Some(p) = name.strip_prefix(['p', 'P']) {
+            let (whole, fraction) = p.split_once('.').unwrap_or((p, "0"));
+            if digits(whole) && digits(fraction) {
+                let p: f64 = p.parse().unwrap_or(f64::MAX);
+                if (0.0..=100.0).contains(&p) {
+                    // round off the float noise so p99.9 gives a quantile label of 0.999
+                    return Statistic::Percentile((p * 10_000.0).round() / 1_000_000.0);
+                }
+            }
+        }
+        // TM(10%:90%) -> tm_10_90, IQM -> iqm
+        let mut suffix = String::new();
+        for c in name.to_lowercase().chars() {
+            if c.is_ascii_alphanumeric() {
+                suffix.push(c);
+            } else if !suffix.is_empty() && !suffix.ends_with('_') {
+                suffix.push('_');
+            }
+        }
+        Statistic::Other(suffix.trim_end_matches('_').to_string())
+    }
+}
+
+#[test]
+fn test_statistic_parse() {
+    assert_eq!(Statistic::parse("p99"), Statistic::Percentile(0.99));
+    assert_eq!(Statistic::parse("p99.9"), Statistic::Percentile(0.999));
+    assert_eq!(Statistic::parse("tm99"), Statistic::Other("tm99".to_string()));
+    assert_eq!(Statistic::parse("TM(10%:90%)"), Statistic::Other("tm_10_90".to_string()));
+    assert_eq!(Statistic::parse("IQM"), Statistic::Other("iqm".to_string()));
+    assert_eq!(Statistic::parse("PR(:300)"), Statistic::Other("pr_300".to_string()));
+    for name in ["p1e1", "pNaN", "p+50", "p99.", "p.5", "pinf"] {
+        assert!(matches!(Statistic::parse(name), Statistic::Other(_)), "{name}");
+    }
+}
+
+#[test]
+fn test_additional_statistics_skip_non_numbers() {
+    let value: MetricValue =
+        serde_json::from_str(r#"{"max": 1.0, "p99": 0.5, "note": "text", "extra": {"a": 1}}"#).unwrap();
+    assert_eq!(value.max, Some(1.0));
+    assert_eq!(value.additional, BTreeMap::from([("p99".to_string(), 0.5)]));
+}
@@ -0,0 +1,102 @@
+use crate::batch::{MetricBatch, Source};
+use crate::config::{TenantConfig, TenantRule};
+use axum::http::HeaderName;
+
+/// The tenant a request is for, and the header that tells the receiver so.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tenant {
+    pub(crate) header: HeaderName,
+    pub(crate) id: String,
+}
+
+/// Decides which tenant each series goes to, for one destination.
+#[derive(Debug, Clone)]
+pub struct TenantRouting {
+    pub(crate) header: HeaderName,
+    default: String,
+    rules: Vec<TenantRule>,
+}
+
+impl TenantRouting {
+    pub fn from_config(config: &TenantConfig) -> anyhow::Result<Self> {
+        Ok(TenantRouting {
+            header: config.header.parse()?,
+            default: config.default.clone(),
+            rules: config.rules.clone(),
+        })
+    }
+
+    pub fn tenant_for(&self, source: &Source) -> &str {
+        let matches = |rule: &TenantRule| {
+            rule.account_id == source.account_id
+                && rule.region.as_ref().is_none_or(|r| r == &source.region)
+                && rule
+                    .metric_stream_name
+                    .as_ref()
+                    .is_none_or(|s| s == &source.metric_stream_name)
+        };
+        self.rules
+            .iter()
+            .find(|rule| matches(rule))
+            .map_or(self.default.as_str(), |rule| rule.tenant.as_str())
+    }
+
+    pub fn split(&self, batch: MetricBatch) -> Vec<(String, MetricBatch)> {
+        batch
+            .split_by(|source| self.tenant_for(source).to_string())
+            .into_iter()
+            .collect()
+    }
+}
+
+#[test]
+fn test_split_by_tenant() {
+    use crate::structs::CloudWatchMetric;
+    use crate::testing::cpu_metric;
+    let routing = TenantRouting::from_config(&TenantConfig {
+        rules: vec![
+            TenantRule {
+                account_id: "111111111111".to_string(),
+                region: Some("eu-west-1".to_string()),
+                metric_stream_name: None,
+                tenant: "unit-a-eu".to_string(),
+            },
+            TenantRule {
+                account_id: "111111111111".to_string(),
+                region: None,
+                metric_stream_name: None,
+                tenant: "unit-a".to_string(),
+            },
+        ],
+        ..Default::default()
+    })
+    .unwrap();
+    let metric = |account_id: &str, region: &str| CloudWatchMetric {
+        account_id: account_id.to_string(),
+        region: region.to_string(),
+        ..cpu_metric(1.0)
+    };
+    let mut batch = MetricBatch::default();
+    for (account_id, region) in [
+        ("111111111111", "eu-west-1"),
+        ("111111111111", "us-east-1"),
+        ("111111111111", "us-west-2"),
+        ("222222222222", "us-east-1"),
+    ] {
+        batch.add(&metric(account_id, region), &Default::default(), &Default::default());
+    }
+    let mut split: Vec<(String, usize)> = routing
+        .split(batch)
+        .into_iter()
+        .map(|(tenant, batch)| (tenant, batch.samples()))
+        .collect();
+    split.sort();
+    assert_eq!(
+        split,
+        vec![
+            ("anonymous".to_string(), 1),
+            ("unit-a".to_string(), 2),
+            ("unit-a-eu".to_string(), 1)
+        ]
+    );
+}
@@ -0,0 +1,103 @@
+//! Fixtures shared by the tests.
+
+use crate::structs::{CloudWatchMetric, MetricUnit, MetricValue};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, Uri};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs, process};
+
+/// A request as it reached a [`CaptureServer`].
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+type Requests = Arc<Mutex<Vec<Request>>>;
+
+/// Records every request sent to it and answers 200.
+pub struct CaptureServer {
+    pub addr: SocketAddr,
+    requests: Requests,
+}
+
+impl CaptureServer {
+    pub async fn start() -> Self {
+        Self::start_on(SocketAddr::from(([127, 0, 0, 1], 0))).await
+    }
+
+    pub async fn start_on(addr: SocketAddr) -> Self {
+        async fn capture(State(requests): State<Requests>, uri: Uri, headers: HeaderMap, body: Bytes) {
+            requests.lock().unwrap().push(Request { uri, headers, body });
+        }
+        let requests = Requests::default();
+        let app = axum::Router::new()
+            .fallback(capture)
+            .with_state(Arc::clone(&requests));
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { addr, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Waits up to ten seconds for at least `count` requests.
+    pub async fn wait_for(&self, count: usize) -> Vec<Request> {
+        for _ in 0..1_000 {
+            let requests = self.requests();
+            if requests.len() >= count {
+                return requests;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("expected {count} requests, got {}", self.requests().len());
+    }
+}
+
+/// An empty directory for one test, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("firehose-test-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// EC2 CPUUtilization with just a max, for tests that only need a metric.
+pub fn cpu_metric(max: f64) -> CloudWatchMetric {
+    CloudWatchMetric {
+        namespace: "AWS/EC2".to_string(),
+        metric_name: "CPUUtilization".to_string(),
+        value: MetricValue {
+            max: Some(max),
+            ..Default::default()
+        },
+        unit: MetricUnit::Percent,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,138 @@
+use crate::config::TlsConfig;
+use crate::prometheus::TLS_RELOADS;
+use axum_server::tls_rustls::RustlsConfig;
+use std::fs;
+use std::time::Duration;
+
+/// The certificate chain and key as last read, to tell when either has changed.
+type Pem = (Vec<u8>, Vec<u8>);
+
+fn read_pem(config: &TlsConfig) -> anyhow::Result<Pem> {
+    let cert = fs::read(&config.cert_file)
+        .map_err(|e| anyhow!("Couldn't read tls certificate {:?}: {e}", config.cert_file))?;
+    let key = fs::read(&config.key_file).map_err(|e| anyhow!("Couldn't read tls key {:?}: {e}", config.key_file))?;
+    Ok((cert, key))
+}
+
+/// Loads the certificate for the https listener, and keeps it current in the background.
+pub async fn load_tls(config: &TlsConfig, interval: Duration) -> anyhow::Result<RustlsConfig> {
+    // axum-server is built without a crypto provider, rustls needs telling which one to use
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let (cert, key) = read_pem(config)?;
+    let rustls = RustlsConfig::from_pem(cert.clone(), key.clone())
+        .await
+        .map_err(|e| anyhow!("Couldn't load tls certificate {:?}: {e}", config.cert_file))?;
+    tokio::spawn(watch(rustls.clone(), config.clone(), (cert, key), interval));
+    Ok(rustls)
+}
+
+/// Polls the files, like [`Reloader::watch`](crate::reload::Reloader::watch).  A half-rotated pair
+/// fails to load, so the current certificate stays until the files change again.
+async fn watch(rustls: RustlsConfig, config: TlsConfig, mut loaded: Pem, interval: Duration) {
+    let mut failed: Option<Pem> = None;
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let pem = match read_pem(&config) {
+            Ok(pem) => pem,
+            Err(e) => {
+                warn!("{e}");
+                continue;
+            }
+        };
+        if pem == loaded || Some(&pem) == failed.as_ref() {
+            continue;
+        }
+        match rustls.reload_from_pem(pem.0.clone(), pem.1.clone()).await {
+            Ok(_) => {
+                info!("Reloaded tls certificate from {:?}.", config.cert_file);
+                TLS_RELOADS.with_label_values(&["success"]).inc();
+                loaded = pem;
+                failed = None;
+            }
+            Err(e) => {
+                error!("Couldn't reload tls certificate, carrying on with the current one: {e}");
+                TLS_RELOADS.with_label_values(&["failure"]).inc();
+                failed = Some(pem);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_certificate_rotation_is_picked_up() {
+    use crate::client::{build_client, request_url};
+    use crate::config::{ClientTlsConfig, DestinationConfig};
+    use std::path::PathBuf;
+
+    let dir = std::env::temp_dir().join(format!("firehose-test-tls-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let generate = |name: &str| {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = dir.join(format!("{name}.crt"));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        (cert, generated.cert.pem(), generated.key_pair.serialize_pem())
+    };
+    let (first_ca, first_cert, first_key) = generate("first");
+    let (second_ca, second_cert, second_key) = generate("second");
+    let config = TlsConfig {
+        listen: String::new(),
+        cert_file: dir.join("tls.crt"),
+        key_file: dir.join("tls.key"),
+    };
+    fs::write(&config.cert_file, &first_cert).unwrap();
+    fs::write(&config.key_file, &first_key).unwrap();
+
+    let rustls = load_tls(&config, Duration::from_millis(50)).await.unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = axum::Router::new().fallback(|| async { "" });
+    tokio::spawn(axum_server::from_tcp_rustls(listener, rustls).serve(app.into_make_service()));
+
+    // a fresh client per request, trusting only the one certificate, so every request is a new
+    // handshake that only succeeds if that's the certificate being served
+    let serves = |ca: PathBuf| async move {
+        let destination = DestinationConfig {
+            addr: format!("https://{addr}"),
+            tls: Some(ClientTlsConfig {
+                ca_file: Some(ca),
+                server_name: Some("localhost".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let client = build_client(&destination).unwrap();
+        client.get(request_url(&destination).unwrap()).send().await.is_ok()
+    };
+    assert!(serves(first_ca.clone()).await);
+    assert!(!serves(second_ca.clone()).await);
+
+    fs::write(&config.cert_file, &second_cert).unwrap();
+    fs::write(&config.key_file, &second_key).unwrap();
+    let mut rotated = false;
+    for _ in 0..100 {
+        if serves(second_ca.clone()).await {
+            rotated = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(rotated, "the new certificate was never served");
+    assert!(!serves(first_ca.clone()).await);
+
+    // half a rotation: a certificate that doesn't go with the key keeps the current one in use
+    let failures = || TLS_RELOADS.with_label_values(&["failure"]).get();
+    let failed_before = failures();
+    fs::write(&config.cert_file, &first_cert).unwrap();
+    for _ in 0..100 {
+        if failures() > failed_before {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(failures() > failed_before, "the mismatched pair was never tried");
+    assert!(serves(second_ca).await);
+    assert!(!serves(first_ca).await);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,273 @@
+use crate::config::WalConfig;
+#[cfg(test)]
+use crate::consts::DEFAULT_DESTINATION;
+use crate::prometheus::{WAL_BYTES, WAL_DROPPED_SEGMENTS, WAL_SEGMENTS};
+use crate::remote_write::RemoteWriteProtocol;
+use crate::tenant::Tenant;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const SEGMENT_EXTENSION: &str = "wal";
+const TENANT_EXTENSION: &str = "tenant";
+
+/// Kept next to a segment written for a tenant, so that it replays to the same tenant whatever
+/// the config says by then, and with no limit on how long a tenant id can be.
+#[derive(Debug, Serialize, Deserialize)]
+struct TenantFile {
+    header: String,
+    tenant: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalSettings {
+    pub(crate) destination: String,
+    pub(crate) dir: PathBuf,
+    pub(crate) max_bytes: u64,
+    pub(crate) max_age: Duration,
+}
+
+impl WalSettings {
+    pub fn from_config(destination: &str, config: &WalConfig) -> Self {
+        WalSettings {
+            destination: destination.to_string(),
+            dir: config.dir.clone(),
+            max_bytes: config.max_bytes,
+            max_age: Duration::from_secs(config.max_age_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    /// What the batch was encoded as, in case the destination's protocol changed since.
+    pub(crate) protocol: RemoteWriteProtocol,
+    modified: SystemTime,
+}
+
+/// A directory of encoded write requests, one file per batch, named by a sequence number so that
+/// they replay in the order they were written.  A segment stays on disk until the receiver has
+/// either accepted or permanently rejected it.
+#[derive(Debug)]
+pub struct Wal {
+    settings: WalSettings,
+    next_seq: u64,
+}
+
+impl Wal {
+    pub fn open(settings: WalSettings) -> anyhow::Result<Self> {
+        fs::create_dir_all(&settings.dir)
+            .map_err(|e| anyhow!("Couldn't create wal directory {:?}: {e}", settings.dir))?;
+        let mut wal = Wal {
+            settings,
+            next_seq: 0,
+        };
+        wal.remove_orphaned_tenant_files()?;
+        let segments = wal.segments()?;
+        wal.next_seq = segments
+            .last()
+            .and_then(|s| segment_seq(&s.path))
+            .map_or(0, |seq| seq + 1);
+        if !segments.is_empty() {
+            info!(
+                "Found {} unsent batches in wal {:?}, they will be replayed.",
+                segments.len(),
+                wal.settings.dir
+            );
+        }
+        wal.update_metrics(&segments);
+        Ok(wal)
+    }
+
+    /// Tenant files whose segment never got written, or was removed without them.  Left where
+    /// they are, a new segment with the same sequence number would pick one up.
+    fn remove_orphaned_tenant_files(&self) -> anyhow::Result<()> {
+        for entry in fs::read_dir(&self.settings.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(TENANT_EXTENSION)
+                && !path.with_extension(SEGMENT_EXTENSION).exists()
+            {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a batch to a new segment via a temporary file, so a crash never leaves a truncated
+    /// one.  2.0 segments are `{seq}.v2.wal`; a tenant's `.tenant` file is written first.
+    pub fn append(&mut self, body: &[u8], protocol: RemoteWriteProtocol, tenant: Option<&Tenant>) -> anyhow::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let mut name = format!("{seq:020}");
+        if protocol == RemoteWriteProtocol::V2 {
+            name.push_str(".v2");
+        }
+        let path = self.settings.dir.join(format!("{name}.{SEGMENT_EXTENSION}"));
+        if let Some(tenant) = tenant {
+            let contents = serde_json::to_vec(&TenantFile {
+                header: tenant.header.to_string(),
+                tenant: tenant.id.clone(),
+            })?;
+            let mut file = fs::File::create(path.with_extension(TENANT_EXTENSION))?;
+            file.write_all(&contents)?;
+            file.sync_all()?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(body)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        self.enforce_retention()?;
+        Ok(())
+    }
+
+    /// Every segment still waiting to be sent, oldest first.
+    pub fn segments(&self) -> anyhow::Result<Vec<Segment>> {
+        let mut segments: Vec<Segment> = vec![];
+        for entry in fs::read_dir(&self.settings.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+            let protocol = match stem.ends_with(".v2") {
+                true => RemoteWriteProtocol::V2,
+                false => RemoteWriteProtocol::V1,
+            };
+            segments.push(Segment {
+                path,
+                size: metadata.len(),
+                protocol,
+                modified: metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            });
+        }
+        segments.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(segments)
+    }
+
+    /// A segment's body, and the tenant it was written for if any.
+    pub fn read(&self, segment: &Segment) -> anyhow::Result<(Vec<u8>, Option<Tenant>)> {
+        let body = fs::read(&segment.path)?;
+        let tenant = match fs::read(segment.path.with_extension(TENANT_EXTENSION)) {
+            Ok(contents) => {
+                let file: TenantFile = serde_json::from_slice(&contents)?;
+                Some(Tenant {
+                    header: file.header.parse()?,
+                    id: file.tenant,
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok((body, tenant))
+    }
+
+    pub fn remove(&self, segment: &Segment) {
+        match delete_segment(&segment.path) {
+            // retention got to it first
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Couldn't remove wal segment {:?}: {e}", segment.path),
+            Ok(_) => {}
+        }
+        if let Ok(segments) = self.segments() {
+            self.update_metrics(&segments);
+        }
+    }
+
+    /// Drops the oldest segments once the wal is over its size budget, and any that have been
+    /// waiting longer than the receiver would likely accept anyway.
+    fn enforce_retention(&self) -> anyhow::Result<()> {
+        let mut segments = self.segments()?;
+        let now = SystemTime::now();
+        segments.retain(|s| {
+            let age = now.duration_since(s.modified).unwrap_or_default();
+            if age <= self.settings.max_age {
+                return true;
+            }
+            warn!("Dropping wal segment {:?}, it is {age:?} old.", s.path);
+            WAL_DROPPED_SEGMENTS
+                .with_label_values(&[&self.settings.destination, "age"])
+                .inc();
+            let _ = delete_segment(&s.path);
+            false
+        });
+        let mut total: u64 = segments.iter().map(|s| s.size).sum();
+        while total > self.settings.max_bytes && segments.len() > 1 {
+            let oldest = segments.remove(0);
+            warn!("Dropping wal segment {:?}, wal is over {} bytes.", oldest.path, self.settings.max_bytes);
+            WAL_DROPPED_SEGMENTS
+                .with_label_values(&[&self.settings.destination, "size"])
+                .inc();
+            let _ = delete_segment(&oldest.path);
+            total -= oldest.size;
+        }
+        self.update_metrics(&segments);
+        Ok(())
+    }
+
+    fn update_metrics(&self, segments: &[Segment]) {
+        let destination = self.settings.destination.as_str();
+        WAL_SEGMENTS
+            .with_label_values(&[destination])
+            .set(segments.len() as f64);
+        WAL_BYTES
+            .with_label_values(&[destination])
+            .set(segments.iter().map(|s| s.size).sum::<u64>() as f64);
+    }
+}
+
+/// Removes a segment, then its tenant file.  If it stops in between, the tenant file is left
+/// without a segment, and goes the next time the wal is opened.
+fn delete_segment(path: &Path) -> std::io::Result<()> {
+    fs::remove_file(path)?;
+    match fs::remove_file(path.with_extension(TENANT_EXTENSION)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn segment_seq(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    name.split('.').next()?.parse().ok()
+}
+
+#[test]
+fn test_wal_replays_in_order_and_survives_reopen() {
+    let dir = std::env::temp_dir().join(format!("firehose-wal-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let settings = WalSettings {
+        destination: DEFAULT_DESTINATION.to_string(),
+        dir: dir.clone(),
+        max_bytes: 11,
+        max_age: Duration::from_secs(3600),
+    };
+    let mut wal = Wal::open(settings.clone()).unwrap();
+    wal.append(b"first", RemoteWriteProtocol::V1, None).unwrap();
+    wal.append(b"second", RemoteWriteProtocol::V1, None).unwrap();
+    // over the size budget, so "first" has to go
+    // far longer than a file name can be
+    let tenant = Tenant {
+        header: "X-Scope-OrgID".parse().unwrap(),
+        id: "unit.a|".repeat(60),
+    };
+    wal.append(b"third", RemoteWriteProtocol::V2, Some(&tenant)).unwrap();
+
+    let mut wal = Wal::open(settings).unwrap();
+    let segments = wal.segments().unwrap();
+    let read: Vec<(Vec<u8>, Option<Tenant>)> = segments.iter().map(|s| wal.read(s).unwrap()).collect();
+    assert_eq!(read, vec![(b"second".to_vec(), None), (b"third".to_vec(), Some(tenant))]);
+    assert_eq!(segments[1].protocol, RemoteWriteProtocol::V2);
+
+    wal.append(b"4", RemoteWriteProtocol::V1, None).unwrap();
+    let last = wal.segments().unwrap().pop().unwrap();
+    assert_eq!(segment_seq(&last.path), Some(3));
+    wal.remove(&segments[1]);
+    assert!(!segments[1].path.with_extension(TENANT_EXTENSION).exists());
+    fs::remove_dir_all(&dir).unwrap();
+}